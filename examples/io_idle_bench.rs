@@ -0,0 +1,60 @@
+// Connects a bunch of idle IO clients to a running server and measures the server's CPU usage while nothing is sent.
+// Linux only, since it reads the server's CPU time from /proc.
+//
+// IO_API_KEY=<api key> cargo run --release --example io_idle_bench -- <server pid> [clients = 500] [seconds = 10] [host = 127.0.0.1:3000]
+use std::{env, fs, time::{Duration, Instant}};
+use serde_json::{json, Value};
+
+// USER_HZ, which is 100 on pretty much every Linux system
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+fn cpu_ticks(pid: u32) -> u64 {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).expect("Failed to read the server's /proc stat");
+    // The process name can contain spaces, so skip past it before splitting
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split_whitespace().collect();
+    // utime and stime, fields 14 and 15 of the full line
+    fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+}
+
+async fn request_token(client: &reqwest::Client, host: &str, api_key: &str, username: &str) -> String {
+    let response = client.post(format!("http://{}/v1/websocket/io/token", host))
+        .header("x-api-key", api_key)
+        .header("Content-Type", "application/json")
+        .body(json!({ "username": username }).to_string())
+        .send()
+        .await
+        .expect("Failed to connect to the server");
+    let body: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap_or(Value::Null);
+    body["token"].as_str().expect("Server didn't return a token, is IO_API_KEY valid?").to_string()
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let pid: u32 = args.get(1).expect("Usage: io_idle_bench <server pid> [clients] [seconds] [url]").parse().expect("pid is not a number");
+    let clients: usize = args.get(2).map(|value| value.parse().unwrap()).unwrap_or(500);
+    let seconds: u64 = args.get(3).map(|value| value.parse().unwrap()).unwrap_or(10);
+    let host = args.get(4).cloned().unwrap_or("127.0.0.1:3000".to_string());
+    let api_key = env::var("IO_API_KEY").expect("IO_API_KEY needs to be set to request websocket tokens");
+
+    let client = reqwest::Client::new();
+    let mut connections = Vec::with_capacity(clients);
+    for index in 0..clients {
+        let username = format!("bench{}", index);
+        let token = request_token(&client, &host, &api_key, &username).await;
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/websocket/io/{}?token={}", host, username, token))
+            .await
+            .expect("Failed to connect to the server");
+        connections.push(socket);
+    }
+    println!("Connected {} IO clients, measuring for {} seconds.", clients, seconds);
+
+    let before = cpu_ticks(pid);
+    let started = Instant::now();
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    let used = (cpu_ticks(pid) - before) as f64 / CLOCK_TICKS_PER_SECOND;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    println!("Server used {:.2}s of CPU in {:.2}s ({:.1}% of one core) with {} idle clients.", used, elapsed, used / elapsed * 100.0, clients);
+    drop(connections);
+}
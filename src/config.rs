@@ -0,0 +1,9 @@
+use std::{env, str::FromStr};
+
+// Reads an optional setting from the environment, refusing to start on garbage values
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.parse::<T>().unwrap_or_else(|_| panic!("Server cannot start: {} is not a valid value", key)),
+        Err(_) => default
+    }
+}
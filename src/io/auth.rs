@@ -0,0 +1,61 @@
+use std::{sync::Arc, time::Duration};
+use serde::{Deserialize, Serialize};
+use crate::util::random_hex;
+use super::bus::IoBus;
+
+// Anyone holding a token can connect as its user, so it comes straight from the OS random number generator
+const TOKEN_BYTES: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+struct IssuedToken {
+    username: String,
+    // When set, the connection can only join this room
+    room: Option<String>
+}
+
+pub enum TokenError {
+    // Unknown or expired, or issued for another username or room
+    Invalid,
+    // The bus couldn't be asked about it
+    Unavailable(String)
+}
+
+// Short-lived tokens binding a websocket connection to a username.
+// Game servers request one through the API (with their API key) and hand it to the player's IO client.
+// They're kept on the bus, so the client can connect to whichever instance it ends up on.
+pub struct IoTokens {
+    bus: Arc<dyn IoBus>
+}
+
+fn token_key(token: &str) -> String {
+    format!("token:{}", token)
+}
+
+impl IoTokens {
+    pub fn new(bus: Arc<dyn IoBus>) -> Self {
+        Self { bus }
+    }
+
+    pub async fn issue(&self, username: &str, room: Option<String>, ttl: Duration) -> Result<String, String> {
+        let token = random_hex(TOKEN_BYTES);
+        let issued = serde_json::to_string(&IssuedToken { username: username.to_string(), room }).unwrap();
+        self.bus.store(token_key(&token), issued, ttl).await?;
+        Ok(token)
+    }
+
+    // Tokens stay valid until they expire, so clients can reconnect with the same one.
+    // Returns the room the connection ends up in.
+    pub async fn validate(&self, token: &str, username: &str, room: Option<String>) -> Result<Option<String>, TokenError> {
+        let issued = self.bus.load(token_key(token)).await.map_err(TokenError::Unavailable)?;
+        let issued = match issued.and_then(|issued| serde_json::from_str::<IssuedToken>(&issued).ok()) {
+            Some(issued) if issued.username == username => issued,
+            _ => return Err(TokenError::Invalid)
+        };
+
+        match (issued.room, room) {
+            (Some(bound), Some(room)) if bound != room => Err(TokenError::Invalid),
+            (Some(bound), _) => Ok(Some(bound)),
+            (None, room) => Ok(room)
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+use futures_util::{future::BoxFuture, StreamExt};
+use redis::{aio::ConnectionManager, AsyncCommands, Client, RedisError, RedisResult, Value};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, OnceCell};
+use crate::util::random_hex;
+use super::{DeliveryStatus, IoObserverEvent, WebsocketIoStruct, INSTANCE_BITS};
+
+// Redis channel every instance publishes to and listens on
+const REDIS_CHANNEL: &str = "liquid_breakout:io";
+// Every key the bus sets starts with this
+const REDIS_KEY_PREFIX: &str = "liquid_breakout:io:";
+// Instance ids are leased through keys named `instance:` plus the id, and renewed well before the lease runs out
+const INSTANCE_LEASE: Duration = Duration::from_secs(30);
+// Startup waits this long for an instance id, after that it goes on with a random one, see `lease_instance`
+const STARTUP_LEASE_TIMEOUT: Duration = Duration::from_secs(10);
+// Each connection attempt and command gives up after this
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+// How long to wait before retrying after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+// Events waiting to be published, anything past that is dropped while the broker can't keep up
+const PUBLISH_QUEUE_SIZE: usize = 4096;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum IoBusEvent {
+    // Should go to the target's connections on every instance
+    Deliver { message: WebsocketIoStruct },
+    // Every instance holds on to the message until `at`, then gives it to its own connections of the target
+    Schedule { message: WebsocketIoStruct, at: u64 },
+    // A scheduled message was cancelled, no instance should send it anymore
+    Cancel { id: u64 },
+    // An instance handed a message to its connections, or its client acknowledged it
+    Status { id: u64, status: DeliveryStatus },
+    // A username got its first, or lost its last, connection in a room on the sending instance
+    RoomJoin { room: String, username: String },
+    RoomLeave { room: String, username: String },
+    // Everyone in a room on the sending instance, sent every so often so the others catch up on what they missed
+    Rooms { rooms: HashMap<String, Vec<String>> },
+    // Everyone should be taken out of the room, on every instance
+    RoomClose { room: String },
+    // Traffic the sending instance showed its own observers, so observers on every instance see all of it
+    Observed { traffic: IoObserverEvent }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IoBusEnvelope {
+    // Instance the event came from, so it can skip its own events
+    pub origin: u64,
+    pub event: IoBusEvent
+}
+
+// Carries IO events between the instances of the server, and keeps the values they all need to see.
+// Every subscriber sees every published event, including the ones its own instance published.
+pub trait IoBus: Send + Sync {
+    // Id for a hub on this bus, no other hub on it has the same one. Always below 2^INSTANCE_BITS, since it
+    // ends up in message ids.
+    fn claim_instance(&self) -> u64;
+    fn publish(&self, envelope: IoBusEnvelope);
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<IoBusEnvelope>;
+    // Keeps `value` under `key` until `ttl` runs out, every instance reads the same one back from `load`
+    fn store(&self, key: String, value: String, ttl: Duration) -> BoxFuture<'_, Result<(), String>>;
+    fn load(&self, key: String) -> BoxFuture<'_, Result<Option<String>, String>>;
+    // Puts `value` in the set under `key` at `rank`, replacing what was there, and keeps only the `keep` highest
+    // ranked values. Ranks go up to 2^53, Redis holds them as doubles.
+    fn insert_ranked(&self, key: String, rank: u64, value: String, keep: usize) -> BoxFuture<'_, Result<(), String>>;
+    // Values ranked `min` to `max` (inclusive), highest ranked first, skipping `offset` and returning at most `count`
+    fn ranked_between(&self, key: String, min: u64, max: u64, offset: usize, count: usize) -> BoxFuture<'_, Result<Vec<String>, String>>;
+}
+
+// In-process bus, the default when running a single instance.
+// Hubs sharing one also talk to each other like separate instances would.
+#[derive(Default)]
+pub struct LocalBus {
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<IoBusEnvelope>>>,
+    next_instance: AtomicU64,
+    values: Mutex<HashMap<String, (String, Instant)>>,
+    ranked: Mutex<HashMap<String, BTreeMap<u64, String>>>
+}
+
+impl LocalBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IoBus for LocalBus {
+    fn claim_instance(&self) -> u64 {
+        let instance = self.next_instance.fetch_add(1, Ordering::Relaxed);
+        assert!(instance < 1 << INSTANCE_BITS, "more hubs on one LocalBus than there are instance ids");
+        instance
+    }
+
+    fn publish(&self, envelope: IoBusEnvelope) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(envelope.clone()).is_ok());
+    }
+
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<IoBusEnvelope> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn store(&self, key: String, value: String, ttl: Duration) -> BoxFuture<'_, Result<(), String>> {
+        let now = Instant::now();
+        let mut values = self.values.lock().unwrap();
+        values.retain(|_, (_, expires_at)| *expires_at > now);
+        values.insert(key, (value, now + ttl));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load(&self, key: String) -> BoxFuture<'_, Result<Option<String>, String>> {
+        let values = self.values.lock().unwrap();
+        let value = values.get(&key).filter(|(_, expires_at)| *expires_at > Instant::now()).map(|(value, _)| value.clone());
+        Box::pin(async { Ok(value) })
+    }
+
+    fn insert_ranked(&self, key: String, rank: u64, value: String, keep: usize) -> BoxFuture<'_, Result<(), String>> {
+        let mut ranked = self.ranked.lock().unwrap();
+        let values = ranked.entry(key).or_default();
+        values.insert(rank, value);
+        while values.len() > keep {
+            values.pop_first();
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn ranked_between(&self, key: String, min: u64, max: u64, offset: usize, count: usize) -> BoxFuture<'_, Result<Vec<String>, String>> {
+        let ranked = self.ranked.lock().unwrap();
+        let values = match ranked.get(&key) {
+            Some(values) if min <= max => values.range(min..=max).rev().skip(offset).take(count).map(|(_, value)| value.clone()).collect(),
+            _ => Vec::new()
+        };
+        Box::pin(async { Ok(values) })
+    }
+}
+
+fn redis_key(key: &str) -> String {
+    format!("{}{}", REDIS_KEY_PREFIX, key)
+}
+
+fn timed_out() -> RedisError {
+    std::io::Error::from(std::io::ErrorKind::TimedOut).into()
+}
+
+// Commands share one multiplexed connection, made on first use so startup doesn't hang on the broker.
+// Once it's there, it reconnects by itself.
+struct RedisCommands {
+    client: Client,
+    connection: OnceCell<ConnectionManager>
+}
+
+impl RedisCommands {
+    async fn connection(&self) -> RedisResult<ConnectionManager> {
+        let connection = self.connection.get_or_try_init(|| {
+            ConnectionManager::new_with_backoff_and_timeouts(self.client.clone(), 2, 100, 3, COMMAND_TIMEOUT, COMMAND_TIMEOUT)
+        }).await?;
+        Ok(connection.clone())
+    }
+}
+
+// Sets the instance id's key to `owner` if nobody holds it, returning whether we got it
+async fn try_lease(connection: &mut ConnectionManager, instance: u64, owner: &str) -> RedisResult<bool> {
+    let reply: Value = redis::cmd("SET")
+        .arg(redis_key(&format!("instance:{}", instance)))
+        .arg(owner)
+        .arg("NX")
+        .arg("PX")
+        .arg(INSTANCE_LEASE.as_millis() as u64)
+        .query_async(connection)
+        .await?;
+    Ok(reply == Value::Okay)
+}
+
+// Takes the first free instance id. Boot only waits STARTUP_LEASE_TIMEOUT for the broker, past that this
+// picks a random id and `renew_instance` keeps trying to lease it.
+async fn lease_instance(commands: &RedisCommands, owner: &str) -> u64 {
+    let leased = tokio::time::timeout(STARTUP_LEASE_TIMEOUT, async {
+        loop {
+            let leased: RedisResult<Option<u64>> = async {
+                let mut connection = commands.connection().await?;
+                for instance in 0..1 << INSTANCE_BITS {
+                    if try_lease(&mut connection, instance, owner).await? {
+                        return Ok(Some(instance))
+                    }
+                }
+                Ok(None)
+            }.await;
+
+            match leased {
+                Ok(Some(instance)) => return instance,
+                Ok(None) => println!("IO bus has no free instance id, all {} are in use", 1u64 << INSTANCE_BITS),
+                Err(e) => println!("IO bus could not lease an instance id: {}", e)
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }).await;
+
+    leased.unwrap_or_else(|_| {
+        let instance = u64::from_str_radix(&random_hex(1), 16).unwrap() % (1 << INSTANCE_BITS);
+        println!("IO bus is starting as instance {} without a lease, message ids may collide until it gets one", instance);
+        instance
+    })
+}
+
+// Keeps the lease for as long as the process runs. If it ran out anyway because the broker was gone for too
+// long, it's taken again unless another instance got there first.
+async fn renew_instance(commands: Arc<RedisCommands>, instance: u64, owner: String) {
+    let key = redis_key(&format!("instance:{}", instance));
+    loop {
+        tokio::time::sleep(INSTANCE_LEASE / 3).await;
+        let renewed: RedisResult<bool> = async {
+            let mut connection = commands.connection().await?;
+            let holder: Option<String> = connection.get(&key).await?;
+            match holder {
+                Some(holder) if holder == owner => connection.pexpire(&key, INSTANCE_LEASE.as_millis() as i64).await,
+                Some(_) => Ok(false),
+                None => try_lease(&mut connection, instance, &owner).await
+            }
+        }.await;
+
+        match renewed {
+            Ok(true) => {},
+            Ok(false) => println!("IO bus instance id {} is held by another instance, message ids may collide", instance),
+            Err(e) => println!("IO bus could not renew instance id {}: {}", instance, e)
+        }
+    }
+}
+
+// Sends queued events one at a time, holding on to each until the broker takes it
+async fn publish_events(commands: Arc<RedisCommands>, mut queue: mpsc::Receiver<String>, dropped: Arc<AtomicU64>) {
+    while let Some(payload) = queue.recv().await {
+        loop {
+            let published: RedisResult<i64> = async {
+                let mut connection = commands.connection().await?;
+                connection.publish(REDIS_CHANNEL, &payload).await
+            }.await;
+            match published {
+                Ok(_) => break,
+                Err(e) => {
+                    println!("IO bus could not publish: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            println!("IO bus dropped {} events while its publish queue was full", dropped);
+        }
+    }
+}
+
+// Shares events between instances through Redis pub/sub, and keeps shared values as Redis keys.
+// A local `redis-server` is enough to try it out: run two instances with the same IO_BUS_URL.
+pub struct RedisBus {
+    client: Client,
+    commands: Arc<RedisCommands>,
+    // Leased from the broker, this process only ever runs one hub
+    instance: u64,
+    outgoing: mpsc::Sender<String>,
+    // Events that didn't fit in the publish queue since the publisher last reported them
+    dropped: Arc<AtomicU64>
+}
+
+impl RedisBus {
+    // Leases an instance id from the broker, giving up on that after STARTUP_LEASE_TIMEOUT.
+    // Publishing and renewing the lease happen in their own tasks.
+    pub async fn connect(url: &str) -> Self {
+        let client = Client::open(url).expect("Server cannot start: IO_BUS_URL is not a valid redis:// url");
+        let commands = Arc::new(RedisCommands { client: client.clone(), connection: OnceCell::new() });
+        // Tells our lease apart from one another instance took after ours ran out
+        let owner = random_hex(8);
+        let instance = lease_instance(&commands, &owner).await;
+        tokio::spawn(renew_instance(commands.clone(), instance, owner));
+
+        let (outgoing, queue) = mpsc::channel(PUBLISH_QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(publish_events(commands.clone(), queue, dropped.clone()));
+
+        Self { client, commands, instance, outgoing, dropped }
+    }
+}
+
+impl IoBus for RedisBus {
+    fn claim_instance(&self) -> u64 {
+        self.instance
+    }
+
+    fn publish(&self, envelope: IoBusEnvelope) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.outgoing.try_send(serde_json::to_string(&envelope).unwrap()) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<IoBusEnvelope> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let client = self.client.clone();
+
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let subscribed: RedisResult<()> = async {
+                    let mut pubsub = tokio::time::timeout(COMMAND_TIMEOUT, client.get_async_pubsub()).await.map_err(|_| timed_out())??;
+                    pubsub.subscribe(REDIS_CHANNEL).await?;
+                    let mut messages = pubsub.on_message();
+                    while let Some(message) = messages.next().await {
+                        let payload: String = message.get_payload()?;
+                        match serde_json::from_str::<IoBusEnvelope>(&payload) {
+                            Ok(envelope) => {
+                                if sender.send(envelope).is_err() {
+                                    return Ok(())
+                                }
+                            },
+                            Err(e) => println!("IO bus received an invalid event: {}", e)
+                        }
+                    }
+                    Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+                }.await;
+
+                if let Err(e) = subscribed {
+                    println!("IO bus lost its subscription: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        });
+        receiver
+    }
+
+    fn store(&self, key: String, value: String, ttl: Duration) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let mut connection = self.commands.connection().await.map_err(|e| e.to_string())?;
+            connection.pset_ex(redis_key(&key), value, ttl.as_millis() as u64).await.map_err(|e| e.to_string())
+        })
+    }
+
+    fn load(&self, key: String) -> BoxFuture<'_, Result<Option<String>, String>> {
+        Box::pin(async move {
+            let mut connection = self.commands.connection().await.map_err(|e| e.to_string())?;
+            connection.get(redis_key(&key)).await.map_err(|e| e.to_string())
+        })
+    }
+
+    fn insert_ranked(&self, key: String, rank: u64, value: String, keep: usize) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let mut connection = self.commands.connection().await.map_err(|e| e.to_string())?;
+            let key = redis_key(&key);
+            redis::pipe()
+                .cmd("ZREMRANGEBYSCORE").arg(&key).arg(rank).arg(rank).ignore()
+                .cmd("ZADD").arg(&key).arg(rank).arg(value).ignore()
+                .cmd("ZREMRANGEBYRANK").arg(&key).arg(0).arg(-(keep as i64) - 1).ignore()
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn ranked_between(&self, key: String, min: u64, max: u64, offset: usize, count: usize) -> BoxFuture<'_, Result<Vec<String>, String>> {
+        Box::pin(async move {
+            let mut connection = self.commands.connection().await.map_err(|e| e.to_string())?;
+            redis::cmd("ZREVRANGEBYSCORE").arg(redis_key(&key)).arg(max).arg(min).arg("LIMIT").arg(offset).arg(count)
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
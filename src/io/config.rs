@@ -0,0 +1,93 @@
+use std::{env, time::Duration};
+use crate::config::env_or;
+use super::queue::OverflowPolicy;
+
+#[derive(Clone, Debug)]
+pub struct IoConfig {
+    // How long sent messages are kept around for clients that reconnect with `last_id`
+    pub retention: Duration,
+    pub max_retained_per_user: usize,
+    // How many messages can wait on a single connection, and what happens to the ones past that
+    pub max_queued_per_connection: usize,
+    // Connections one username can have open on an instance, each with a queue of its own. 0 turns the limit off.
+    pub max_connections_per_user: usize,
+    pub overflow_policy: OverflowPolicy,
+    // Default and longest lifetime of websocket tokens
+    pub token_ttl: Duration,
+    pub max_token_ttl: Duration,
+    // Websocket pings are sent this often, connections that stay silent for `idle_timeout` get closed
+    pub heartbeat_interval: Duration,
+    pub idle_timeout: Duration,
+    // How far ahead a message can be scheduled, and how many can be waiting at once. Every instance holds a copy of
+    // every scheduled message, so the count covers all of them.
+    pub max_schedule_ahead: Duration,
+    pub max_scheduled: usize,
+    // Messages an API key can send, and a single username can be sent, per `rate_limit_window`. 0 turns a limit off.
+    // Every instance counts on its own, so N instances sharing a bus allow up to N times these.
+    pub rate_limit_per_key: u32,
+    pub rate_limit_per_target: u32,
+    pub rate_limit_window: Duration,
+    // How many sent messages the history endpoint remembers, 0 turns it off
+    pub history_size: usize,
+    // `redis://` url of the broker shared between instances, messages stay in this process without one
+    pub bus_url: Option<String>
+}
+
+impl Default for IoConfig {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(120),
+            max_retained_per_user: 100,
+            max_queued_per_connection: 256,
+            max_connections_per_user: 4,
+            overflow_policy: OverflowPolicy::DropOldest,
+            token_ttl: Duration::from_secs(60 * 60),
+            max_token_ttl: Duration::from_secs(24 * 60 * 60),
+            heartbeat_interval: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(45),
+            max_schedule_ahead: Duration::from_secs(60 * 60),
+            max_scheduled: 10_000,
+            rate_limit_per_key: 200,
+            rate_limit_per_target: 10,
+            rate_limit_window: Duration::from_secs(1),
+            history_size: 10_000,
+            bus_url: None
+        }
+    }
+}
+
+impl IoConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        // The connection tasks can't tick every 0 seconds, so refuse it here instead of panicking on every connection
+        let heartbeat_interval = Duration::from_secs(env_or("IO_HEARTBEAT_SECONDS", default.heartbeat_interval.as_secs()));
+        if heartbeat_interval.is_zero() {
+            panic!("Server cannot start: IO_HEARTBEAT_SECONDS is not a valid value");
+        }
+        // Same for tokens, they'd be expired as soon as they're issued
+        let token_ttl = Duration::from_secs(env_or("IO_TOKEN_TTL_SECONDS", default.token_ttl.as_secs()));
+        let max_token_ttl = Duration::from_secs(env_or("IO_MAX_TOKEN_TTL_SECONDS", default.max_token_ttl.as_secs()));
+        if token_ttl.is_zero() || max_token_ttl.is_zero() {
+            panic!("Server cannot start: IO_TOKEN_TTL_SECONDS and IO_MAX_TOKEN_TTL_SECONDS have to be at least 1");
+        }
+
+        Self {
+            retention: Duration::from_secs(env_or("IO_RETENTION_SECONDS", default.retention.as_secs())),
+            max_retained_per_user: env_or("IO_MAX_RETAINED_PER_USER", default.max_retained_per_user),
+            max_queued_per_connection: env_or("IO_MAX_QUEUED_PER_CONNECTION", default.max_queued_per_connection),
+            max_connections_per_user: env_or("IO_MAX_CONNECTIONS_PER_USER", default.max_connections_per_user),
+            overflow_policy: env_or("IO_OVERFLOW_POLICY", default.overflow_policy),
+            token_ttl,
+            max_token_ttl,
+            heartbeat_interval,
+            idle_timeout: Duration::from_secs(env_or("IO_IDLE_TIMEOUT_SECONDS", default.idle_timeout.as_secs())),
+            max_schedule_ahead: Duration::from_secs(env_or("IO_MAX_SCHEDULE_AHEAD_SECONDS", default.max_schedule_ahead.as_secs())),
+            max_scheduled: env_or("IO_MAX_SCHEDULED", default.max_scheduled),
+            rate_limit_per_key: env_or("IO_RATE_LIMIT_PER_KEY", default.rate_limit_per_key),
+            rate_limit_per_target: env_or("IO_RATE_LIMIT_PER_TARGET", default.rate_limit_per_target),
+            rate_limit_window: Duration::from_secs(env_or("IO_RATE_LIMIT_WINDOW_SECONDS", default.rate_limit_window.as_secs())),
+            history_size: env_or("IO_HISTORY_SIZE", default.history_size),
+            bus_url: env::var("IO_BUS_URL").ok().filter(|url| !url.is_empty())
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use super::{bus::IoBus, first_message_id_at, protocol::IoAction, DeliveryStatus};
+
+// Entries are kept on the bus under this key, ranked by message id
+const HISTORY_KEY: &str = "history";
+// Entries read from the bus at a time while looking for the ones sent to a username
+const USERNAME_PAGE_SIZE: usize = 500;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IoHistoryEntry {
+    pub id: u64,
+    // Unix milliseconds
+    pub sent_at: u64,
+    // Masked API key of whoever sent it, see `mask_api_key`
+    pub sender: String,
+    pub client: String,
+    pub action: IoAction,
+    pub start_time: Option<u64>,
+    // Recorded again whenever it changes, for as long as the sending instance tracks the message
+    pub status: DeliveryStatus
+}
+
+// Enough of a key to tell senders apart, without handing working keys to everyone who can read the history
+pub fn mask_api_key(api_key: &str) -> String {
+    let chars: Vec<char> = api_key.chars().collect();
+    if chars.len() < 8 {
+        return "****".to_string()
+    }
+    format!("****{}", chars[chars.len() - 4..].iter().collect::<String>())
+}
+
+// Every IO message sent through the API, newest first, forgetting the oldest ones past `capacity`.
+// It lives on the bus, so every instance answers with the same history and it outlasts restarts.
+pub struct IoHistory {
+    bus: Arc<dyn IoBus>,
+    capacity: usize
+}
+
+impl IoHistory {
+    pub fn new(bus: Arc<dyn IoBus>, capacity: usize) -> Self {
+        Self { bus, capacity }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // Replaces the entry with the same id, if there is one
+    pub async fn record(&self, entry: &IoHistoryEntry) -> Result<(), String> {
+        if !self.enabled() {
+            return Ok(())
+        }
+        self.bus.insert_ranked(HISTORY_KEY.to_string(), entry.id, serde_json::to_string(entry).unwrap(), self.capacity).await
+    }
+
+    // Newest first, optionally only the ones sent to `username` between `from` and `to` (unix milliseconds, inclusive).
+    // Ids start with the millisecond they were given out at, so the time range is a range of ids the bus reads
+    // on its own. Usernames aren't indexed, those are read a page at a time until enough of them turned up.
+    pub async fn query(&self, username: Option<&str>, from: Option<u64>, to: Option<u64>, limit: usize) -> Result<Vec<IoHistoryEntry>, String> {
+        let min = from.map_or(0, first_message_id_at);
+        let max = to.map_or(u64::MAX, |to| first_message_id_at(to.saturating_add(1)).saturating_sub(1));
+        if limit == 0 || min > max {
+            return Ok(Vec::new())
+        }
+
+        let page_size = if username.is_some() { limit.max(USERNAME_PAGE_SIZE) } else { limit };
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = self.bus.ranked_between(HISTORY_KEY.to_string(), min, max, offset, page_size).await?;
+            offset += page.len();
+            entries.extend(page.iter()
+                .filter_map(|entry| serde_json::from_str::<IoHistoryEntry>(entry).ok())
+                .filter(|entry| match username {
+                    Some(username) => entry.client == username,
+                    None => true
+                }));
+            if entries.len() >= limit || page.len() < page_size {
+                break;
+            }
+        }
+        entries.truncate(limit);
+        Ok(entries)
+    }
+}
@@ -0,0 +1,795 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::{broadcast, watch}, task::AbortHandle};
+use bus::{IoBus, IoBusEnvelope, IoBusEvent};
+use config::IoConfig;
+use protocol::{IoAction, IoClientMessage, IoClientState, IoServerMessage};
+use queue::{IoQueue, Pushed};
+
+pub mod auth;
+pub mod bus;
+pub mod config;
+pub mod history;
+pub mod protocol;
+pub mod queue;
+pub mod rate_limit;
+
+#[cfg(test)]
+mod tests;
+
+// How many message ids we remember the delivery status of
+const MAX_TRACKED_MESSAGES: usize = 10_000;
+// Observer events buffered per observer, slower observers skip ahead
+const OBSERVER_BUFFER: usize = 1024;
+// Error messages reported by clients are cut off past this many characters
+const MAX_CLIENT_ERROR_LENGTH: usize = 500;
+// How often users whose retained messages all expired are forgotten, sends only prune their own target
+const RETAINED_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+// How often every instance tells the others who's in its rooms. Instances that go quiet for a few of these
+// are taken to be gone, along with their members.
+const ROOM_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+const REMOTE_ROOMS_EXPIRY: Duration = Duration::from_secs(30);
+// Message ids count milliseconds from here, see `IoHub::next_message_id`
+const MESSAGE_ID_EPOCH: u64 = 1_704_067_200_000;
+// Bits of a message id taken by the id of the instance that sent it, and by the count within a millisecond
+const INSTANCE_BITS: u32 = 6;
+const COUNTER_BITS: u32 = 6;
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0)
+}
+
+// Lowest message id any instance can give out at `millis` (unix milliseconds), see `IoHub::next_message_id`
+pub fn first_message_id_at(millis: u64) -> u64 {
+    millis.saturating_sub(MESSAGE_ID_EPOCH) << (INSTANCE_BITS + COUNTER_BITS)
+}
+
+// Used for sending data to IO clients
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WebsocketIoStruct {
+    pub id: u64,
+    pub client: String,
+    #[serde(flatten)]
+    pub action: IoAction,
+    pub start_time: Option<u64>
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    // Held back until its `start_time`
+    Scheduled,
+    // Was scheduled, then cancelled before going out
+    Cancelled,
+    // Nobody was connected under the target username
+    Undelivered,
+    // Every connection of the target had a full queue and turned it away
+    Dropped,
+    // Handed to at least one connection, but not acknowledged yet
+    Sent,
+    Acknowledged
+}
+
+// IO traffic as seen by observers, tagged by `event`. Every instance shares its own over the bus, so an observer
+// sees the traffic of all of them.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum IoObserverEvent {
+    // A message went out from the instance it was sent to, `status` is how far it got there right away
+    Send { message: WebsocketIoStruct, status: DeliveryStatus },
+    // A message is held back until `at` (unix milliseconds), it shows up as `send` once it goes out
+    Schedule { message: WebsocketIoStruct, at: u64 },
+    // A message was queued on one of the connections of the instance holding it
+    Deliver { id: u64, client: String, connection: u64, pushed: Pushed },
+    Ack { id: u64, client: String },
+    // The observer fell behind and missed this many events
+    Lagged { skipped: u64 }
+}
+
+struct Connection {
+    id: u64,
+    queue: Arc<IoQueue>,
+    // Room the connection joined, usually the JobId of the game server the player is in
+    room: Option<String>,
+    connected_at: u64,
+    // Shared with the connection's inbox, so heartbeats don't need the hub's lock
+    last_heartbeat: Arc<AtomicU64>,
+    // Messages this connection lost to a full queue
+    overflowed: u64
+}
+
+// A connected username, summed up over all of its connections
+pub struct IoClientInfo {
+    pub username: String,
+    pub connections: usize,
+    pub connected_at: u64,
+    pub last_heartbeat: u64,
+    // Messages queued on its connections that haven't been written to the socket yet
+    pub pending_messages: usize,
+    // Messages its connections lost to a full queue
+    pub overflowed_messages: u64
+}
+
+struct TrackedMessage {
+    client: String,
+    status: watch::Sender<DeliveryStatus>
+}
+
+struct RetainedMessage {
+    message: WebsocketIoStruct,
+    sent_at: Instant
+}
+
+// Who's in which room on another instance, as last heard over the bus
+struct RemoteRooms {
+    rooms: HashMap<String, HashSet<String>>,
+    heard_at: Instant
+}
+
+#[derive(Default)]
+struct Tracker {
+    messages: HashMap<u64, TrackedMessage>,
+    order: VecDeque<u64>
+}
+
+// Routes IO messages from the API to the websocket connections of the username they're meant for.
+// Every connection gets its own inbox and sleeps on it until something arrives.
+// Messages also go out over the bus, so connections held by other instances get them too.
+pub struct IoHub {
+    config: IoConfig,
+    bus: Arc<dyn IoBus>,
+    // Id of this instance on the bus, no other instance has the same one
+    instance: u64,
+    connections: Mutex<HashMap<String, Vec<Connection>>>,
+    // Recently sent messages per username, replayed to clients that reconnect with a resume cursor
+    retained: Mutex<HashMap<String, VecDeque<RetainedMessage>>>,
+    tracker: Mutex<Tracker>,
+    // Only kept while the user has a connection, so it can't grow with everyone who ever connected
+    client_states: Mutex<HashMap<String, IoClientState>>,
+    // Messages waiting for their start time, by message id. Every instance holds its own copy of each.
+    scheduled: Mutex<HashMap<u64, AbortHandle>>,
+    // Room members on other instances, by instance id
+    remote_rooms: Mutex<HashMap<u64, RemoteRooms>>,
+    next_connection_id: AtomicU64,
+    last_message_id: AtomicU64,
+    // Messages lost to full queues since startup, over all connections
+    overflowed_messages: AtomicU64,
+    // Websocket tasks and SSE streams still running, see `ConnectionTask`
+    connection_tasks: AtomicU64,
+    observers: broadcast::Sender<IoObserverEvent>
+}
+
+impl IoHub {
+    // Starts listening on the bus right away, so this needs to be called from within the tokio runtime
+    pub fn new(config: IoConfig, bus: Arc<dyn IoBus>) -> Arc<Self> {
+        let mut events = bus.subscribe();
+        let hub = Arc::new(Self {
+            config,
+            instance: bus.claim_instance(),
+            bus,
+            connections: Mutex::new(HashMap::new()),
+            retained: Mutex::new(HashMap::new()),
+            tracker: Mutex::new(Tracker::default()),
+            client_states: Mutex::new(HashMap::new()),
+            scheduled: Mutex::new(HashMap::new()),
+            remote_rooms: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicU64::new(0),
+            last_message_id: AtomicU64::new(0),
+            overflowed_messages: AtomicU64::new(0),
+            connection_tasks: AtomicU64::new(0),
+            observers: broadcast::channel(OBSERVER_BUFFER).0
+        });
+
+        let weak_hub = Arc::downgrade(&hub);
+        tokio::spawn(async move {
+            while let Some(envelope) = events.recv().await {
+                let Some(hub) = weak_hub.upgrade() else {
+                    break;
+                };
+                hub.handle_bus_event(envelope);
+            }
+        });
+
+        let weak_hub = Arc::downgrade(&hub);
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval(RETAINED_SWEEP_INTERVAL);
+            loop {
+                sweep.tick().await;
+                let Some(hub) = weak_hub.upgrade() else {
+                    break;
+                };
+                hub.sweep_retained();
+            }
+        });
+
+        let weak_hub = Arc::downgrade(&hub);
+        tokio::spawn(async move {
+            let mut snapshot = tokio::time::interval(ROOM_SNAPSHOT_INTERVAL);
+            loop {
+                snapshot.tick().await;
+                let Some(hub) = weak_hub.upgrade() else {
+                    break;
+                };
+                hub.share_rooms();
+            }
+        });
+        hub
+    }
+
+    pub fn config(&self) -> &IoConfig {
+        &self.config
+    }
+
+    pub fn bus(&self) -> Arc<dyn IoBus> {
+        self.bus.clone()
+    }
+
+    // Ids have to be unique across instances and keep going up for `last_id` to work, so they're built from
+    // milliseconds since MESSAGE_ID_EPOCH, the instance id and a counter, in that order.
+    // That stays below 2^53 until 2093, which clients that only have doubles can still read exactly.
+    pub fn next_message_id(&self) -> u64 {
+        let millis_shift = INSTANCE_BITS + COUNTER_BITS;
+        let instance = self.instance << COUNTER_BITS;
+        let base = first_message_id_at(unix_millis()) | instance;
+        let next = |last: u64| {
+            if last < base {
+                base
+            } else if last & ((1 << COUNTER_BITS) - 1) < (1 << COUNTER_BITS) - 1 {
+                last + 1
+            } else {
+                // Out of ids for that millisecond. Carrying would change the instance bits, so take the next
+                // millisecond's first id instead, the clock catches up once the burst is over.
+                (((last >> millis_shift) + 1) << millis_shift) | instance
+            }
+        };
+        let last = self.last_message_id.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(next(last))).unwrap();
+        next(last)
+    }
+
+    pub fn observe(&self) -> broadcast::Receiver<IoObserverEvent> {
+        self.observers.subscribe()
+    }
+
+    // Observers here get the event right away, the ones on other instances through the bus
+    fn notify_observers(&self, event: IoObserverEvent) {
+        self.publish(IoBusEvent::Observed { traffic: event.clone() });
+        let _ = self.observers.send(event);
+    }
+
+    fn publish(&self, event: IoBusEvent) {
+        self.bus.publish(IoBusEnvelope { origin: self.instance, event });
+    }
+
+    fn handle_bus_event(self: &Arc<Self>, envelope: IoBusEnvelope) {
+        if envelope.origin == self.instance {
+            return
+        }
+
+        match envelope.event {
+            IoBusEvent::Deliver { message } => {
+                self.dispatch(&message, true);
+            },
+            IoBusEvent::Schedule { message, at } => {
+                self.schedule_dispatch(message, at, true);
+            },
+            IoBusEvent::Cancel { id } => {
+                self.cancel_local(id);
+            },
+            IoBusEvent::Status { id, status: status @ (DeliveryStatus::Sent | DeliveryStatus::Dropped) } => self.mark(id, status),
+            IoBusEvent::Status { id, status: DeliveryStatus::Acknowledged } => {
+                let tracker = self.tracker.lock().unwrap();
+                if let Some(message) = tracker.messages.get(&id) {
+                    message.status.send_replace(DeliveryStatus::Acknowledged);
+                }
+            },
+            IoBusEvent::Status { .. } => {},
+            IoBusEvent::RoomJoin { room, username } => {
+                let mut remote_rooms = self.remote_rooms.lock().unwrap();
+                let instance = remote_rooms.entry(envelope.origin).or_insert_with(|| RemoteRooms { rooms: HashMap::new(), heard_at: Instant::now() });
+                instance.rooms.entry(room).or_default().insert(username);
+                instance.heard_at = Instant::now();
+            },
+            IoBusEvent::RoomLeave { room, username } => {
+                let mut remote_rooms = self.remote_rooms.lock().unwrap();
+                if let Some(instance) = remote_rooms.get_mut(&envelope.origin) {
+                    if let Some(members) = instance.rooms.get_mut(&room) {
+                        members.remove(&username);
+                        if members.is_empty() {
+                            instance.rooms.remove(&room);
+                        }
+                    }
+                    instance.heard_at = Instant::now();
+                }
+            },
+            IoBusEvent::Rooms { rooms } => {
+                let rooms = rooms.into_iter().map(|(room, members)| (room, members.into_iter().collect())).collect();
+                self.remote_rooms.lock().unwrap().insert(envelope.origin, RemoteRooms { rooms, heard_at: Instant::now() });
+            },
+            IoBusEvent::RoomClose { room } => self.close_local_room(&room),
+            IoBusEvent::Observed { traffic } => {
+                let _ = self.observers.send(traffic);
+            }
+        }
+    }
+
+    // Everyone in a room on this instance, by room
+    fn local_rooms(&self) -> HashMap<String, Vec<String>> {
+        let connections = self.connections.lock().unwrap();
+        let mut rooms: HashMap<String, Vec<String>> = HashMap::new();
+        for (username, user_connections) in connections.iter() {
+            let user_rooms: HashSet<&String> = user_connections.iter().filter_map(|connection| connection.room.as_ref()).collect();
+            for room in user_rooms {
+                rooms.entry(room.clone()).or_default().push(username.clone());
+            }
+        }
+        rooms
+    }
+
+    // Sends this instance's rooms to the others, and forgets the members of instances that went quiet
+    fn share_rooms(&self) {
+        self.publish(IoBusEvent::Rooms { rooms: self.local_rooms() });
+        let mut remote_rooms = self.remote_rooms.lock().unwrap();
+        remote_rooms.retain(|_, instance| instance.heard_at.elapsed() < REMOTE_ROOMS_EXPIRY);
+    }
+
+    // Registers a connection for `username`, it stays registered until the returned inbox is dropped.
+    // With `last_id` set, retained messages newer than it are queued up first, in the order they were sent.
+    // Returns None when the username already has as many connections as it's allowed
+    pub fn register(self: &Arc<Self>, username: &str, room: Option<String>, last_id: Option<u64>) -> Option<IoInbox> {
+        // Holding the connections lock the whole time means nothing sent meanwhile is missed or replayed twice
+        let mut connections = self.connections.lock().unwrap();
+        let max_connections = self.config.max_connections_per_user;
+        if max_connections > 0 && connections.get(username).is_some_and(|user_connections| user_connections.len() >= max_connections) {
+            return None
+        }
+
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(IoQueue::new(self.config.max_queued_per_connection, self.config.overflow_policy));
+        let mut overflowed = 0;
+        let now = unix_millis();
+        let last_heartbeat = Arc::new(AtomicU64::new(now));
+        let mut replayed = Vec::new();
+        if let Some(last_id) = last_id {
+            let mut retained = self.retained.lock().unwrap();
+            if let Some(messages) = retained.get_mut(username) {
+                self.prune_retained(messages);
+                for retained_message in messages.iter().filter(|retained_message| retained_message.message.id > last_id) {
+                    match queue.push(retained_message.message.clone()) {
+                        Pushed::Queued => replayed.push(retained_message.message.id),
+                        Pushed::DroppedOldest => {
+                            replayed.push(retained_message.message.id);
+                            overflowed += 1;
+                        },
+                        Pushed::Rejected => overflowed += 1
+                    }
+                }
+            }
+        }
+        // Published under the lock, so a join and a leave of the same user can't swap places on the bus
+        let user_connections = connections.entry(username.to_string()).or_default();
+        if let Some(room) = &room {
+            if !user_connections.iter().any(|connection| connection.room.as_ref() == Some(room)) {
+                self.publish(IoBusEvent::RoomJoin { room: room.clone(), username: username.to_string() });
+            }
+        }
+        user_connections.push(Connection {
+            id,
+            queue: queue.clone(),
+            room,
+            connected_at: now,
+            last_heartbeat: last_heartbeat.clone(),
+            overflowed
+        });
+        drop(connections);
+        self.overflowed_messages.fetch_add(overflowed, Ordering::Relaxed);
+
+        for message_id in replayed {
+            self.mark(message_id, DeliveryStatus::Sent);
+        }
+
+        Some(IoInbox { hub: self.clone(), username: username.to_string(), id, queue, last_heartbeat })
+    }
+
+    pub fn clients(&self) -> Vec<IoClientInfo> {
+        let connections = self.connections.lock().unwrap();
+        connections.iter().map(|(username, user_connections)| IoClientInfo {
+            username: username.clone(),
+            connections: user_connections.len(),
+            connected_at: user_connections.iter().map(|connection| connection.connected_at).min().unwrap_or(0),
+            last_heartbeat: user_connections.iter().map(|connection| connection.last_heartbeat.load(Ordering::Relaxed)).max().unwrap_or(0),
+            pending_messages: user_connections.iter().map(|connection| connection.queue.len()).sum(),
+            overflowed_messages: user_connections.iter().map(|connection| connection.overflowed).sum()
+        }).collect()
+    }
+
+    pub fn overflowed_messages(&self) -> u64 {
+        self.overflowed_messages.load(Ordering::Relaxed)
+    }
+
+    // Counts the calling websocket task or SSE stream as running until the returned guard is dropped
+    pub fn connection_task(self: &Arc<Self>) -> ConnectionTask {
+        self.connection_tasks.fetch_add(1, Ordering::Relaxed);
+        ConnectionTask { hub: self.clone() }
+    }
+
+    pub fn connection_tasks(&self) -> u64 {
+        self.connection_tasks.load(Ordering::Relaxed)
+    }
+
+    // Messages still waiting for their start time, from every instance
+    pub fn scheduled_count(&self) -> usize {
+        self.scheduled.lock().unwrap().len()
+    }
+
+    // Drops one user's messages that are past the retention period
+    fn prune_retained(&self, messages: &mut VecDeque<RetainedMessage>) {
+        while messages.front().is_some_and(|oldest| oldest.sent_at.elapsed() > self.config.retention) {
+            messages.pop_front();
+        }
+    }
+
+    // Forgets everyone whose retained messages have all expired
+    fn sweep_retained(&self) {
+        let mut retained = self.retained.lock().unwrap();
+        retained.retain(|_, messages| {
+            self.prune_retained(messages);
+            !messages.is_empty()
+        });
+    }
+
+    // Runs on every send with the connections lock held, so it only touches the target's own messages
+    fn retain(&self, message: &WebsocketIoStruct) {
+        let mut retained = self.retained.lock().unwrap();
+        let messages = retained.entry(message.client.clone()).or_default();
+        self.prune_retained(messages);
+        if messages.len() >= self.config.max_retained_per_user {
+            messages.pop_front();
+        }
+        messages.push_back(RetainedMessage { message: message.clone(), sent_at: Instant::now() });
+    }
+
+    fn unregister(&self, username: &str, id: u64) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(user_connections) = connections.get_mut(username) {
+            let room = user_connections.iter().find(|connection| connection.id == id).and_then(|connection| connection.room.clone());
+            user_connections.retain(|connection| connection.id != id);
+            if let Some(room) = room {
+                if !user_connections.iter().any(|connection| connection.room.as_ref() == Some(&room)) {
+                    self.publish(IoBusEvent::RoomLeave { room, username: username.to_string() });
+                }
+            }
+            if user_connections.is_empty() {
+                connections.remove(username);
+                drop(connections);
+                self.client_states.lock().unwrap().remove(username);
+            }
+        }
+    }
+
+    // Usernames with at least one connection in `room`, on any instance. Members on other instances are
+    // only known once their instance told us about them, which takes a moment.
+    pub fn room_members(&self, room: &str) -> Vec<String> {
+        let connections = self.connections.lock().unwrap();
+        let mut members: Vec<String> = connections.iter()
+            .filter(|(_, user_connections)| user_connections.iter().any(|connection| connection.room.as_deref() == Some(room)))
+            .map(|(username, _)| username.clone())
+            .collect();
+        drop(connections);
+
+        let remote_rooms = self.remote_rooms.lock().unwrap();
+        members.extend(remote_rooms.values().filter_map(|instance| instance.rooms.get(room)).flatten().cloned());
+        members.sort();
+        members.dedup();
+        members
+    }
+
+    // Takes every connection out of `room` on every instance, they stay connected for messages sent to them
+    // directly. Returns how many members it had.
+    pub fn close_room(&self, room: &str) -> usize {
+        let members = self.room_members(room).len();
+        self.close_local_room(room);
+        self.publish(IoBusEvent::RoomClose { room: room.to_string() });
+        members
+    }
+
+    fn close_local_room(&self, room: &str) {
+        let mut connections = self.connections.lock().unwrap();
+        for connection in connections.values_mut().flatten() {
+            if connection.room.as_deref() == Some(room) {
+                connection.room = None;
+            }
+        }
+        drop(connections);
+
+        let mut remote_rooms = self.remote_rooms.lock().unwrap();
+        for instance in remote_rooms.values_mut() {
+            instance.rooms.remove(room);
+        }
+    }
+
+    fn track(&self, id: u64, client: String, status: DeliveryStatus) {
+        let mut tracker = self.tracker.lock().unwrap();
+        // Scheduled messages are already tracked by the time they go out. Another instance's copy may have gone
+        // out first and reported back, that's kept.
+        if let Some(message) = tracker.messages.get(&id) {
+            message.status.send_if_modified(|current| {
+                let modified = *current == DeliveryStatus::Scheduled;
+                if modified {
+                    *current = status;
+                }
+                modified
+            });
+            return
+        }
+
+        if tracker.order.len() >= MAX_TRACKED_MESSAGES {
+            if let Some(oldest) = tracker.order.pop_front() {
+                tracker.messages.remove(&oldest);
+            }
+        }
+        tracker.order.push_back(id);
+        tracker.messages.insert(id, TrackedMessage { client, status: watch::channel(status).0 });
+    }
+
+    // Records how a delivery attempt went, `Sent` or `Dropped`
+    fn mark(&self, id: u64, new_status: DeliveryStatus) {
+        let tracker = self.tracker.lock().unwrap();
+        if let Some(message) = tracker.messages.get(&id) {
+            // The client may have been quick enough to acknowledge already, and a connection on another
+            // instance taking the message counts for more than one here turning it away. Scheduled copies on
+            // other instances can go out before this one's does.
+            message.status.send_if_modified(|status| {
+                let modified = match *status {
+                    DeliveryStatus::Undelivered | DeliveryStatus::Scheduled => true,
+                    DeliveryStatus::Dropped => new_status == DeliveryStatus::Sent,
+                    _ => false
+                };
+                if modified {
+                    *status = new_status;
+                }
+                modified
+            });
+        }
+    }
+
+    // Queues the message on this instance's connections of the target.
+    // Returns `Sent` if any of them took it, `Dropped` if they all turned it away, None without connections.
+    fn deliver_local(&self, message: &WebsocketIoStruct) -> Option<DeliveryStatus> {
+        let mut connections = self.connections.lock().unwrap();
+        self.retain(message);
+
+        let user_connections = connections.get_mut(&message.client)?;
+        let mut status = DeliveryStatus::Dropped;
+        for connection in user_connections.iter_mut() {
+            let pushed = connection.queue.push(message.clone());
+            self.notify_observers(IoObserverEvent::Deliver { id: message.id, client: message.client.clone(), connection: connection.id, pushed });
+            if pushed != Pushed::Rejected {
+                status = DeliveryStatus::Sent;
+            }
+            if pushed != Pushed::Queued {
+                connection.overflowed += 1;
+                self.overflowed_messages.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Some(status)
+    }
+
+    // Hands the message to every connection of its target, returning how far it got.
+    // Deliveries on other instances only show up in the status once they report back over the bus.
+    pub fn send(&self, message: WebsocketIoStruct) -> DeliveryStatus {
+        let status = self.dispatch(&message, false);
+        self.publish(IoBusEvent::Deliver { message });
+        status
+    }
+
+    // Gives the message to this instance's connections of the target. Instances report back how that went
+    // for messages sent from another instance, observers see the ones sent from this one.
+    fn dispatch(&self, message: &WebsocketIoStruct, remote: bool) -> DeliveryStatus {
+        let id = message.id;
+        // Tracked before it goes out so an early ack isn't lost
+        self.track(id, message.client.clone(), DeliveryStatus::Undelivered);
+
+        let delivered = self.deliver_local(message);
+        if let Some(status) = delivered {
+            self.mark(id, status);
+            if remote {
+                self.publish(IoBusEvent::Status { id, status });
+            }
+        }
+        let status = self.status(id).unwrap_or(DeliveryStatus::Undelivered);
+
+        if !remote {
+            self.notify_observers(IoObserverEvent::Send { message: message.clone(), status });
+        }
+        status
+    }
+
+    // Holds the message until `at` (unix milliseconds), sending it right away if that's already passed.
+    // Every instance holds a copy and sends it to its own connections, so it can be cancelled from any of them.
+    pub fn schedule(self: &Arc<Self>, message: WebsocketIoStruct, at: u64) -> DeliveryStatus {
+        self.schedule_dispatch(message, at, false)
+    }
+
+    fn schedule_dispatch(self: &Arc<Self>, message: WebsocketIoStruct, at: u64, remote: bool) -> DeliveryStatus {
+        let delay = at.saturating_sub(unix_millis());
+        if delay == 0 {
+            return if remote { self.dispatch(&message, true) } else { self.send(message) }
+        }
+
+        let id = message.id;
+        self.track(id, message.client.clone(), DeliveryStatus::Scheduled);
+        if !remote {
+            self.notify_observers(IoObserverEvent::Schedule { message: message.clone(), at });
+            self.publish(IoBusEvent::Schedule { message: message.clone(), at });
+        }
+
+        // Holding the lock while spawning, so the task can't look for itself before it's in the map
+        let mut scheduled = self.scheduled.lock().unwrap();
+        let hub = self.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            // Whoever takes it out of the map first wins, this or `cancel`
+            let still_scheduled = hub.scheduled.lock().unwrap().remove(&id).is_some();
+            if still_scheduled {
+                hub.dispatch(&message, remote);
+            }
+        });
+        scheduled.insert(id, task.abort_handle());
+        DeliveryStatus::Scheduled
+    }
+
+    // Cancels a scheduled message on every instance, returning false if it wasn't scheduled (anymore)
+    pub fn cancel(&self, id: u64) -> bool {
+        let cancelled = self.cancel_local(id);
+        if cancelled {
+            self.publish(IoBusEvent::Cancel { id });
+        }
+        cancelled
+    }
+
+    fn cancel_local(&self, id: u64) -> bool {
+        let task = self.scheduled.lock().unwrap().remove(&id);
+        match task {
+            Some(task) => {
+                task.abort();
+                let tracker = self.tracker.lock().unwrap();
+                if let Some(message) = tracker.messages.get(&id) {
+                    message.status.send_replace(DeliveryStatus::Cancelled);
+                }
+                true
+            },
+            None => false
+        }
+    }
+
+    // Only the username a message was sent to can acknowledge it
+    pub fn acknowledge(&self, username: &str, id: u64) -> bool {
+        let acknowledged = {
+            let tracker = self.tracker.lock().unwrap();
+            match tracker.messages.get(&id) {
+                Some(message) if message.client == username => {
+                    message.status.send_replace(DeliveryStatus::Acknowledged);
+                    true
+                },
+                _ => false
+            }
+        };
+        if acknowledged {
+            self.notify_observers(IoObserverEvent::Ack { id, client: username.to_string() });
+            self.publish(IoBusEvent::Status { id, status: DeliveryStatus::Acknowledged });
+        }
+        acknowledged
+    }
+
+    // Handles a message from `username`'s client, returning what should be sent back, if anything
+    pub fn handle_client_message(&self, username: &str, message: IoClientMessage) -> Option<IoServerMessage> {
+        let now = unix_millis();
+        // Only users with a connection get a state, `unregister` clears it again once the last one closes.
+        // Looking that up under the connections lock means a state can't be added back after that.
+        let connections = self.connections.lock().unwrap();
+        let mut client_states = self.client_states.lock().unwrap();
+        let mut state = if connections.contains_key(username) {
+            Some(client_states.entry(username.to_string()).or_default())
+        } else {
+            None
+        };
+        drop(connections);
+        if let Some(state) = state.as_mut() {
+            state.last_seen = now;
+        }
+
+        match message {
+            IoClientMessage::Ack { id } => {
+                drop(client_states);
+                self.acknowledge(username, id);
+                None
+            },
+            IoClientMessage::Ping { nonce } => Some(IoServerMessage::Pong { nonce, server_time: now }),
+            IoClientMessage::TimeSync { client_time } => {
+                drop(client_states);
+                Some(IoServerMessage::TimeSync { client_time, server_receive_time: now, server_send_time: unix_millis() })
+            },
+            IoClientMessage::Status { bgm, started_at, playing } => {
+                if let Some(state) = state {
+                    state.bgm = bgm;
+                    state.bgm_started_at = started_at;
+                    state.playing = playing;
+                }
+                None
+            },
+            IoClientMessage::Error { mut message, id } => {
+                if let Some(state) = state {
+                    // The client decides what's in here, so only so much of it is kept
+                    if let Some((cut, _)) = message.char_indices().nth(MAX_CLIENT_ERROR_LENGTH) {
+                        message.truncate(cut);
+                    }
+                    state.last_error = Some(message);
+                    state.last_error_id = id;
+                }
+                None
+            }
+        }
+    }
+
+    pub fn client_state(&self, username: &str) -> Option<IoClientState> {
+        let client_states = self.client_states.lock().unwrap();
+        client_states.get(username).cloned()
+    }
+
+    pub fn status(&self, id: u64) -> Option<DeliveryStatus> {
+        let tracker = self.tracker.lock().unwrap();
+        tracker.messages.get(&id).map(|message| *message.status.borrow())
+    }
+
+    // Sees every status change of the message, until the hub stops tracking it
+    pub fn subscribe_status(&self, id: u64) -> Option<watch::Receiver<DeliveryStatus>> {
+        let tracker = self.tracker.lock().unwrap();
+        tracker.messages.get(&id).map(|message| message.status.subscribe())
+    }
+
+    // Waits until the message is acknowledged or `timeout` runs out, returning its status at that point
+    pub async fn wait_for_ack(&self, id: u64, timeout: Duration) -> Option<DeliveryStatus> {
+        let mut receiver = self.subscribe_status(id)?;
+
+        let _ = tokio::time::timeout(timeout, receiver.wait_for(|status| *status == DeliveryStatus::Acknowledged)).await;
+        let status = *receiver.borrow();
+        Some(status)
+    }
+}
+
+pub struct IoInbox {
+    hub: Arc<IoHub>,
+    username: String,
+    id: u64,
+    queue: Arc<IoQueue>,
+    last_heartbeat: Arc<AtomicU64>
+}
+
+impl IoInbox {
+    pub async fn recv(&self) -> WebsocketIoStruct {
+        self.queue.pop().await
+    }
+
+    // Marks the connection as alive, shows up as `lastHeartbeat` in the presence API
+    pub fn heartbeat(&self) {
+        self.last_heartbeat.store(unix_millis(), Ordering::Relaxed);
+    }
+}
+
+impl Drop for IoInbox {
+    fn drop(&mut self) {
+        self.hub.unregister(&self.username, self.id);
+    }
+}
+
+// Held for the whole life of a websocket task or SSE stream. A connection unregisters before its socket is closed, so this is
+// what tells a task that really finished apart from one stuck closing its socket.
+pub struct ConnectionTask {
+    hub: Arc<IoHub>
+}
+
+impl Drop for ConnectionTask {
+    fn drop(&mut self) {
+        self.hub.connection_tasks.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// What an IO message tells the client to do. Serialized as `action` plus the action's own fields,
+// e.g. `{"action": "play_bgm", "bgm": "..."}`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum IoAction {
+    PlayBgm { bgm: String },
+    StopBgm,
+    // Clients should line their clocks up with the message's `start_time`
+    SyncTime,
+    ShowMessage { message: String },
+    // Anything game specific, the payload is passed through untouched
+    Custom { payload: Value }
+}
+
+// Messages IO clients send back to us over the websocket, tagged by `type`
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IoClientMessage {
+    // Confirms an IO message with this id arrived
+    Ack { id: u64 },
+    Ping { nonce: Option<u64> },
+    // Clock sync request, `client_time` is the client's own unix time in milliseconds when sending it
+    TimeSync { client_time: u64 },
+    // What the client is currently doing, e.g. which bgm started playing at what time
+    Status {
+        bgm: Option<String>,
+        started_at: Option<u64>,
+        playing: Option<bool>
+    },
+    // Something went wrong on the client, optionally while handling the IO message `id`
+    Error {
+        message: String,
+        id: Option<u64>
+    }
+}
+
+// Replies to client messages. IO messages themselves are sent as plain `WebsocketIoStruct`s.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IoServerMessage {
+    Pong {
+        nonce: Option<u64>,
+        server_time: u64
+    },
+    // NTP style reply. With t0 = client_time, t1 = server_receive_time, t2 = server_send_time and
+    // t3 = the client's time when this arrives:
+    //   offset (server clock - client clock) = ((t1 - t0) + (t2 - t3)) / 2
+    //   round trip delay = (t3 - t0) - (t2 - t1)
+    // Clients should do a few of these and keep the offset from the one with the lowest delay.
+    TimeSync {
+        client_time: u64,
+        server_receive_time: u64,
+        server_send_time: u64
+    },
+    Error {
+        message: String
+    }
+}
+
+// Latest state a client reported for itself
+#[derive(Clone, Default)]
+pub struct IoClientState {
+    pub bgm: Option<String>,
+    pub bgm_started_at: Option<u64>,
+    pub playing: Option<bool>,
+    pub last_error: Option<String>,
+    pub last_error_id: Option<u64>,
+    // Unix time in milliseconds of the last message of any kind from the client
+    pub last_seen: u64
+}
@@ -0,0 +1,85 @@
+use std::{collections::VecDeque, str::FromStr, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use super::WebsocketIoStruct;
+
+// What to do with a message for a connection whose queue is already full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // Make room by dropping the oldest queued message, the client only cares about the latest state anyway
+    DropOldest,
+    // Keep the queue as it is and don't deliver the new message
+    Reject
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "reject" => Ok(Self::Reject),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pushed {
+    Queued,
+    // Queued, but another message had to go to make room
+    DroppedOldest,
+    Rejected
+}
+
+// Messages waiting to be written to one connection.
+// The lock is only held to move messages in and out, never while waiting, so it's fine to use from async code.
+pub struct IoQueue {
+    messages: Mutex<VecDeque<WebsocketIoStruct>>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy
+}
+
+impl IoQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self { messages: Mutex::new(VecDeque::new()), notify: Notify::new(), capacity: capacity.max(1), policy }
+    }
+
+    pub fn push(&self, message: WebsocketIoStruct) -> Pushed {
+        let mut messages = self.messages.lock().unwrap();
+        let pushed = if messages.len() < self.capacity {
+            Pushed::Queued
+        } else {
+            match self.policy {
+                OverflowPolicy::Reject => return Pushed::Rejected,
+                OverflowPolicy::DropOldest => {
+                    messages.pop_front();
+                    Pushed::DroppedOldest
+                }
+            }
+        };
+        messages.push_back(message);
+        drop(messages);
+
+        self.notify.notify_one();
+        pushed
+    }
+
+    // Messages waiting to be written right now
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    // Waits for the next message. Nothing is taken out of the queue until it's returned, so this is safe to
+    // use in `select!`.
+    pub async fn pop(&self) -> WebsocketIoStruct {
+        loop {
+            if let Some(message) = self.messages.lock().unwrap().pop_front() {
+                return message
+            }
+            self.notify.notified().await;
+        }
+    }
+}
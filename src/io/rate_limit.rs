@@ -0,0 +1,79 @@
+use std::{collections::HashMap, sync::{Mutex, MutexGuard}, time::{Duration, Instant}};
+
+// Buckets that are full again carry no information, they get cleared out once there are this many
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+// Token bucket per key: `limit` messages per `window`, and bursts of up to `limit` at once.
+// The buckets live in this process, so with several instances behind the bus each one allows the full limit.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    capacity: f64,
+    refill_per_second: f64
+}
+
+impl RateLimiter {
+    // A limit of 0 turns the limiter off
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            capacity: limit as f64,
+            refill_per_second: limit as f64 / window.as_secs_f64().max(0.001)
+        }
+    }
+
+    // Holds on to the buckets, so any number of keys (and other limiters) can be checked before taking from any of them
+    pub fn lock(&self) -> RateLimitGuard<'_> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.refill_per_second < self.capacity);
+        }
+        RateLimitGuard { limiter: self, buckets, now }
+    }
+}
+
+pub struct RateLimitGuard<'a> {
+    limiter: &'a RateLimiter,
+    buckets: MutexGuard<'a, HashMap<String, Bucket>>,
+    now: Instant
+}
+
+impl RateLimitGuard<'_> {
+    fn bucket(&mut self, key: &str) -> &mut Bucket {
+        let (capacity, refill_per_second, now) = (self.limiter.capacity, self.limiter.refill_per_second, self.now);
+        let bucket = self.buckets.entry(key.to_string()).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill_per_second).min(capacity);
+        bucket.updated = now;
+        bucket
+    }
+
+    // Whether `key`'s bucket has `count` messages' worth in it, or how long until it does.
+    // Asking for more than fits in the bucket needs it to be full, otherwise big batches could never go through.
+    pub fn check(&mut self, key: &str, count: u32) -> Result<(), Duration> {
+        if self.limiter.capacity == 0.0 {
+            return Ok(())
+        }
+
+        let needed = (count as f64).min(self.limiter.capacity);
+        let refill_per_second = self.limiter.refill_per_second;
+        let bucket = self.bucket(key);
+        if bucket.tokens >= needed {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((needed - bucket.tokens) / refill_per_second))
+        }
+    }
+
+    // Takes `count` messages' worth from `key`'s bucket, only call this once `check` passed for it
+    pub fn take(&mut self, key: &str, count: u32) {
+        if self.limiter.capacity == 0.0 {
+            return
+        }
+        self.bucket(key).tokens -= count as f64;
+    }
+}
@@ -0,0 +1,388 @@
+// Two hubs sharing one `LocalBus` behave like two instances of the server sharing a broker
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, sync::mpsc, time::timeout};
+use super::{auth::{IoTokens, TokenError}, bus::{IoBus, LocalBus, RedisBus}, config::IoConfig, first_message_id_at, history::{IoHistory, IoHistoryEntry}, protocol::{IoAction, IoClientMessage}, unix_millis, DeliveryStatus, IoHub, IoInbox, IoObserverEvent, WebsocketIoStruct};
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+const SILENCE_TIMEOUT: Duration = Duration::from_millis(200);
+
+fn instances() -> (Arc<IoHub>, Arc<IoHub>) {
+    let bus = Arc::new(LocalBus::new());
+    (IoHub::new(IoConfig::default(), bus.clone()), IoHub::new(IoConfig::default(), bus))
+}
+
+fn play_bgm(bgm: &str) -> IoAction {
+    IoAction::PlayBgm { bgm: bgm.to_string() }
+}
+
+async fn receive(inbox: &IoInbox) -> WebsocketIoStruct {
+    timeout(RECEIVE_TIMEOUT, inbox.recv()).await.expect("nothing received")
+}
+
+async fn assert_silent(inbox: &IoInbox) {
+    if let Ok(message) = timeout(SILENCE_TIMEOUT, inbox.recv()).await {
+        panic!("unexpected message {} for {}", message.id, message.client);
+    }
+}
+
+// Events take a moment to cross the bus
+async fn eventually(what: &str, condition: impl Fn() -> bool) {
+    let held = timeout(RECEIVE_TIMEOUT, async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await;
+    assert!(held.is_ok(), "{} never happened", what);
+}
+
+#[tokio::test]
+async fn message_ids_stay_unique_across_instances() {
+    let (first, second) = instances();
+    let mut ids = HashSet::new();
+    for hub in [&first, &second] {
+        // Far more than fit in one millisecond
+        let mut last = 0;
+        for _ in 0..5000 {
+            let id = hub.next_message_id();
+            assert!(id > last);
+            assert!(id < 1 << 53);
+            assert!(ids.insert(id), "message id {} was handed out twice", id);
+            last = id;
+        }
+    }
+}
+
+#[tokio::test]
+async fn send_reaches_connections_on_other_instances() {
+    let (first, second) = instances();
+    let alice = second.register("alice", None, None).unwrap();
+    let bob = second.register("bob", None, None).unwrap();
+
+    let message = WebsocketIoStruct { id: first.next_message_id(), client: "alice".to_string(), action: play_bgm("theme"), start_time: None };
+    first.send(message.clone());
+
+    let received = receive(&alice).await;
+    assert_eq!(received.id, message.id);
+    assert_silent(&bob).await;
+
+    // The instance holding the connection reports back, so the sender sees it went out
+    eventually("sender seeing the message go out", || first.status(message.id) == Some(DeliveryStatus::Sent)).await;
+}
+
+#[tokio::test]
+async fn room_members_include_every_instance() {
+    let (first, second) = instances();
+    let _alice = first.register("alice", Some("server-1".to_string()), None).unwrap();
+    let bob = second.register("bob", Some("server-1".to_string()), None).unwrap();
+    let _carol = second.register("carol", Some("server-2".to_string()), None).unwrap();
+    let _dave = second.register("dave", None, None).unwrap();
+
+    eventually("bob joining on the other instance", || first.room_members("server-1") == ["alice", "bob"]).await;
+    assert_eq!(first.room_members("server-2"), ["carol"]);
+
+    drop(bob);
+    eventually("bob leaving on the other instance", || first.room_members("server-1") == ["alice"]).await;
+}
+
+#[tokio::test]
+async fn closing_a_room_reaches_every_instance() {
+    let (first, second) = instances();
+    let alice = second.register("alice", Some("server-1".to_string()), None).unwrap();
+    eventually("alice joining on the other instance", || first.room_members("server-1") == ["alice"]).await;
+
+    assert_eq!(first.close_room("server-1"), 1);
+    assert!(first.room_members("server-1").is_empty());
+    eventually("the room closing on the other instance", || second.room_members("server-1").is_empty()).await;
+
+    // Still connected for messages sent to them directly
+    let message = WebsocketIoStruct { id: first.next_message_id(), client: "alice".to_string(), action: play_bgm("theme"), start_time: None };
+    first.send(message.clone());
+    assert_eq!(receive(&alice).await.id, message.id);
+}
+
+#[tokio::test]
+async fn scheduled_messages_can_be_cancelled_from_any_instance() {
+    let (first, second) = instances();
+    let alice = second.register("alice", None, None).unwrap();
+
+    let at = unix_millis() + 300;
+    let message = WebsocketIoStruct { id: first.next_message_id(), client: "alice".to_string(), action: play_bgm("theme"), start_time: Some(at) };
+    assert_eq!(first.schedule(message.clone(), at), DeliveryStatus::Scheduled);
+    eventually("the other instance holding the message", || second.status(message.id) == Some(DeliveryStatus::Scheduled)).await;
+
+    assert!(second.cancel(message.id));
+    assert!(!second.cancel(message.id));
+    eventually("the sender seeing the cancel", || first.status(message.id) == Some(DeliveryStatus::Cancelled)).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_silent(&alice).await;
+}
+
+#[tokio::test]
+async fn scheduled_messages_go_out_on_every_instance() {
+    let (first, second) = instances();
+    let alice = second.register("alice", None, None).unwrap();
+
+    let at = unix_millis() + 100;
+    let message = WebsocketIoStruct { id: first.next_message_id(), client: "alice".to_string(), action: play_bgm("theme"), start_time: Some(at) };
+    first.schedule(message.clone(), at);
+
+    assert_eq!(receive(&alice).await.id, message.id);
+    assert_silent(&alice).await;
+    eventually("sender seeing the message go out", || first.status(message.id) == Some(DeliveryStatus::Sent)).await;
+}
+
+#[tokio::test]
+async fn observers_see_traffic_from_every_instance() {
+    let (first, second) = instances();
+    let mut events = first.observe();
+    let alice = second.register("alice", None, None).unwrap();
+
+    let message = WebsocketIoStruct { id: second.next_message_id(), client: "alice".to_string(), action: play_bgm("theme"), start_time: None };
+    second.send(message.clone());
+    assert_eq!(receive(&alice).await.id, message.id);
+    assert!(second.acknowledge("alice", message.id));
+
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        seen.push(timeout(RECEIVE_TIMEOUT, events.recv()).await.expect("no observer event").unwrap());
+    }
+    // Queued on alice's connection, sent, then acknowledged, all on the other instance
+    match seen.as_slice() {
+        [IoObserverEvent::Deliver { id: delivered, .. }, IoObserverEvent::Send { message: sent, status: DeliveryStatus::Sent }, IoObserverEvent::Ack { id: acknowledged, .. }] => {
+            assert_eq!([*delivered, sent.id, *acknowledged], [message.id; 3]);
+        },
+        _ => panic!("unexpected observer events")
+    }
+}
+
+#[tokio::test]
+async fn client_state_is_only_kept_while_connected() {
+    let (hub, _) = instances();
+    let status = || IoClientMessage::Status { bgm: Some("theme".to_string()), started_at: None, playing: Some(true) };
+
+    // Posting through the SSE endpoint only needs a token, not a connection
+    hub.handle_client_message("alice", status());
+    assert!(hub.client_state("alice").is_none());
+
+    let alice = hub.register("alice", None, None).unwrap();
+    hub.handle_client_message("alice", status());
+    assert_eq!(hub.client_state("alice").unwrap().bgm.as_deref(), Some("theme"));
+    drop(alice);
+    assert!(hub.client_state("alice").is_none());
+}
+
+// History entries are sent this many milliseconds after HISTORY_START
+const HISTORY_START: u64 = 1_800_000_000_000;
+
+fn history_entry(sent_after: u64, client: &str, status: DeliveryStatus) -> IoHistoryEntry {
+    let sent_at = HISTORY_START + sent_after;
+    IoHistoryEntry { id: first_message_id_at(sent_at), sent_at, sender: "****".to_string(), client: client.to_string(), action: play_bgm("theme"), start_time: None, status }
+}
+
+fn history_summary(entries: Vec<IoHistoryEntry>) -> Vec<(u64, DeliveryStatus)> {
+    entries.into_iter().map(|entry| (entry.sent_at - HISTORY_START, entry.status)).collect()
+}
+
+fn history_times(entries: Vec<IoHistoryEntry>) -> Vec<u64> {
+    entries.into_iter().map(|entry| entry.sent_at - HISTORY_START).collect()
+}
+
+#[tokio::test]
+async fn history_keeps_the_newest_entries_up_to_date() {
+    let history = IoHistory::new(Arc::new(LocalBus::new()), 2);
+    for id in 1..=3 {
+        history.record(&history_entry(id, if id == 2 { "bob" } else { "alice" }, DeliveryStatus::Sent)).await.unwrap();
+    }
+    history.record(&history_entry(3, "alice", DeliveryStatus::Acknowledged)).await.unwrap();
+
+    let entries = history.query(None, None, None, 10).await.unwrap();
+    assert_eq!(history_summary(entries), [(3, DeliveryStatus::Acknowledged), (2, DeliveryStatus::Sent)]);
+    let entries = history.query(Some("bob"), None, None, 10).await.unwrap();
+    assert_eq!(history_summary(entries), [(2, DeliveryStatus::Sent)]);
+    let entries = history.query(None, Some(HISTORY_START + 3), None, 10).await.unwrap();
+    assert_eq!(history_summary(entries), [(3, DeliveryStatus::Acknowledged)]);
+}
+
+#[tokio::test]
+async fn history_reads_only_the_asked_for_range() {
+    let history = IoHistory::new(Arc::new(LocalBus::new()), 100);
+    for sent_after in 1..=10 {
+        history.record(&history_entry(sent_after, if sent_after % 2 == 0 { "bob" } else { "alice" }, DeliveryStatus::Sent)).await.unwrap();
+    }
+
+    let entries = history.query(None, Some(HISTORY_START + 3), Some(HISTORY_START + 6), 10).await.unwrap();
+    assert_eq!(history_times(entries), [6, 5, 4, 3]);
+    let entries = history.query(None, None, Some(HISTORY_START + 6), 2).await.unwrap();
+    assert_eq!(history_times(entries), [6, 5]);
+    let entries = history.query(Some("bob"), None, Some(HISTORY_START + 9), 3).await.unwrap();
+    assert_eq!(history_times(entries), [8, 6, 4]);
+    assert!(history.query(None, Some(HISTORY_START + 6), Some(HISTORY_START + 3), 10).await.unwrap().is_empty());
+}
+
+// Speaks just enough of the Redis protocol for `RedisBus`, keeping values without ever expiring them
+#[derive(Default)]
+struct StandInBroker {
+    values: Mutex<HashMap<String, String>>,
+    ranked: Mutex<HashMap<String, BTreeMap<u64, String>>>,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<String>>>
+}
+
+fn bulk(value: &str) -> String {
+    format!("${}\r\n{}\r\n", value.len(), value)
+}
+
+async fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut arguments = Vec::new();
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let length: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut argument = vec![0; length + 2];
+        reader.read_exact(&mut argument).await.ok()?;
+        argument.truncate(length);
+        arguments.push(String::from_utf8(argument).ok()?);
+    }
+    Some(arguments)
+}
+
+impl StandInBroker {
+    // Returns the url to connect to
+    async fn start() -> (Arc<Self>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let broker = Arc::new(Self::default());
+        let accepting = broker.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(accepting.clone().serve(stream));
+            }
+        });
+        (broker, url)
+    }
+
+    fn subscribers(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+        subscribers.len()
+    }
+
+    async fn serve(self: Arc<Self>, stream: TcpStream) {
+        let mut reader = BufReader::new(stream);
+        while let Some(command) = read_command(&mut reader).await {
+            let reply = match command[0].to_ascii_uppercase().as_str() {
+                "SET" => {
+                    let mut values = self.values.lock().unwrap();
+                    let only_new = command.iter().any(|argument| argument.eq_ignore_ascii_case("NX"));
+                    if only_new && values.contains_key(&command[1]) {
+                        "$-1\r\n".to_string()
+                    } else {
+                        values.insert(command[1].clone(), command[2].clone());
+                        "+OK\r\n".to_string()
+                    }
+                },
+                "PSETEX" => {
+                    self.values.lock().unwrap().insert(command[1].clone(), command[3].clone());
+                    "+OK\r\n".to_string()
+                },
+                "GET" => match self.values.lock().unwrap().get(&command[1]) {
+                    Some(value) => bulk(value),
+                    None => "$-1\r\n".to_string()
+                },
+                "PEXPIRE" => format!(":{}\r\n", self.values.lock().unwrap().contains_key(&command[1]) as u8),
+                // Ranks are only ever whole numbers here
+                "ZADD" => {
+                    self.ranked.lock().unwrap().entry(command[1].clone()).or_default().insert(command[2].parse().unwrap(), command[3].clone());
+                    ":1\r\n".to_string()
+                },
+                "ZREMRANGEBYSCORE" => {
+                    let mut ranked = self.ranked.lock().unwrap();
+                    let removed = ranked.get_mut(&command[1]).and_then(|values| values.remove(&command[2].parse().unwrap()));
+                    format!(":{}\r\n", removed.is_some() as u8)
+                },
+                "ZREMRANGEBYRANK" => {
+                    // Only ever asked to keep the highest ranked, with a negative stop
+                    let keep = (-command[3].parse::<i64>().unwrap() - 1) as usize;
+                    let mut ranked = self.ranked.lock().unwrap();
+                    let values = ranked.entry(command[1].clone()).or_default();
+                    let mut removed = 0;
+                    while values.len() > keep {
+                        values.pop_first();
+                        removed += 1;
+                    }
+                    format!(":{}\r\n", removed)
+                },
+                // Always asked with a LIMIT
+                "ZREVRANGEBYSCORE" => {
+                    let (max, min): (u64, u64) = (command[2].parse().unwrap(), command[3].parse().unwrap());
+                    let (offset, count): (usize, usize) = (command[5].parse().unwrap(), command[6].parse().unwrap());
+                    let ranked = self.ranked.lock().unwrap();
+                    let values: Vec<String> = match ranked.get(&command[1]) {
+                        Some(values) if min <= max => values.range(min..=max).rev().skip(offset).take(count).map(|(_, value)| value.clone()).collect(),
+                        _ => Vec::new()
+                    };
+                    format!("*{}\r\n{}", values.len(), values.iter().map(|value| bulk(value)).collect::<String>())
+                },
+                "PUBLISH" => {
+                    let mut subscribers = self.subscribers.lock().unwrap();
+                    subscribers.retain(|subscriber| subscriber.send(command[2].clone()).is_ok());
+                    format!(":{}\r\n", subscribers.len())
+                },
+                "SUBSCRIBE" => {
+                    let (sender, mut messages) = mpsc::unbounded_channel();
+                    self.subscribers.lock().unwrap().push(sender);
+                    let channel = command[1].clone();
+                    let mut reply = format!("*3\r\n{}{}:1\r\n", bulk("subscribe"), bulk(&channel));
+                    // The connection only gets messages from here on
+                    loop {
+                        if reader.get_mut().write_all(reply.as_bytes()).await.is_err() {
+                            return
+                        }
+                        let Some(payload) = messages.recv().await else {
+                            return
+                        };
+                        reply = format!("*3\r\n{}{}{}", bulk("message"), bulk(&channel), bulk(&payload));
+                    }
+                },
+                // CLIENT SETINFO when connecting
+                "CLIENT" => "+OK\r\n".to_string(),
+                _ => "-ERR unknown command\r\n".to_string()
+            };
+            if reader.get_mut().write_all(reply.as_bytes()).await.is_err() {
+                return
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn redis_bus_connects_instances_through_a_broker() {
+    let (broker, url) = StandInBroker::start().await;
+    let first_bus = Arc::new(RedisBus::connect(&url).await);
+    let second_bus = Arc::new(RedisBus::connect(&url).await);
+    assert_ne!(first_bus.claim_instance(), second_bus.claim_instance());
+    let first = IoHub::new(IoConfig::default(), first_bus.clone());
+    let second = IoHub::new(IoConfig::default(), second_bus.clone());
+    eventually("both instances subscribing", || broker.subscribers() == 2).await;
+
+    // So do the history entries
+    IoHistory::new(first_bus.clone(), 10).record(&history_entry(1, "alice", DeliveryStatus::Sent)).await.unwrap();
+    let entries = IoHistory::new(second_bus.clone(), 10).query(None, None, None, 10).await.unwrap();
+    assert_eq!(history_summary(entries), [(1, DeliveryStatus::Sent)]);
+
+    // Tokens issued on one instance work on the other
+    let token = IoTokens::new(first_bus).issue("alice", Some("server-1".to_string()), Duration::from_secs(60)).await.unwrap();
+    let second_tokens = IoTokens::new(second_bus);
+    assert!(matches!(second_tokens.validate(&token, "alice", None).await, Ok(Some(room)) if room == "server-1"));
+    assert!(matches!(second_tokens.validate(&token, "bob", None).await, Err(TokenError::Invalid)));
+    assert!(matches!(second_tokens.validate("not-a-token", "alice", None).await, Err(TokenError::Invalid)));
+
+    let alice = second.register("alice", Some("server-1".to_string()), None).unwrap();
+    let message = WebsocketIoStruct { id: first.next_message_id(), client: "alice".to_string(), action: play_bgm("theme"), start_time: None };
+    first.send(message.clone());
+    assert_eq!(receive(&alice).await.id, message.id);
+    eventually("sender seeing the message go out", || first.status(message.id) == Some(DeliveryStatus::Sent)).await;
+    eventually("alice joining on the other instance", || first.room_members("server-1") == ["alice"]).await;
+}
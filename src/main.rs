@@ -1,11 +1,17 @@
 use std::{env, sync::Arc};
 use poem::{listener::TcpListener, Route};
 use poem_openapi::OpenApiService;
+use io::config::IoConfig;
 use routes::{apis::ApiRoutes, generic::GenericRoutes};
+use scanner::{download::AssetDownloader, limits::ScanLimits};
 
 use liquid_breakout_backend::Backend;
 
+mod config;
+mod io;
 mod routes;
+mod scanner;
+mod util;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
@@ -16,7 +22,7 @@ async fn main() -> Result<(), std::io::Error> {
     println!("Server starting up.");
 
     let mut backend = Backend::new(
-        roblox_cookie,
+        roblox_cookie.clone(),
         vec![
             "123456789*=+-aAbBcCdDeEfFgGhHiIjJkKlLmMnNoOpPqQrRsStTuUvVwWxXyYzZ".to_string(),
             "0123456789".to_string()
@@ -28,8 +34,9 @@ async fn main() -> Result<(), std::io::Error> {
         Err(e) => panic!("Server cannot start: Failed to connect to MongoDB, reason: {}", (*e).to_string())
     }
 
-    let generic_routes = Arc::new(GenericRoutes::new());
-    let api_routes = ApiRoutes::new(backend, generic_routes.clone());
+    let backend = Arc::new(backend);
+    let generic_routes = Arc::new(GenericRoutes::new(IoConfig::from_env(), backend.clone()).await);
+    let api_routes = ApiRoutes::new(backend, generic_routes.clone(), ScanLimits::from_env(), AssetDownloader::new(roblox_cookie));
 
     let api_service = OpenApiService::new(api_routes, "Liquid Breakout API", "0.0.1")
         .server("https://api.liquidbreakout.com/v1");
@@ -1,15 +1,27 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
 use full_moon::ast::{Call, Expression, FunctionArgs, Suffix};
+use futures_util::future::join_all;
 use poem::Result;
-use poem_openapi::{auth::ApiKey, param::Query, payload::Json, payload::PlainText, OpenApi, SecurityScheme};
+use tokio::sync::Semaphore;
+use poem_openapi::{auth::ApiKey, param::{Path, Query}, payload::{Html, Json, PlainText}, OpenApi, SecurityScheme};
 use line_col::LineColLookup;
 use liquid_breakout_backend::Backend;
-use super::generic::{GenericRoutes, WebsocketIoStruct};
-use super::structs::{ApiError, ApiTags, BanEntryObject, BanListResponse, BanRequestSchema, BanResponse, IdResponse, IoResponse, IoSendSchema, IoSendBatchSchema, MaliciousScriptEntry, ScanMapInfo, ScanMapRequestSchema, ScanMapResponse, ScanMapResult, UnbanRequestSchema, WhitelistInfo, WhitelistRequestSchema, WhitelistResponse};
+use crate::scanner::{aliases, destructive, download::{AssetDownloader, DownloadError}, limits::ScanLimits, report, rules::{self, ScanRule}, store::{self, ScanStore, StoredFinding}};
+use crate::io::{history::{self, IoHistory, IoHistoryEntry}, protocol::IoAction, rate_limit::RateLimiter, unix_millis, DeliveryStatus, WebsocketIoStruct};
+use super::generic::GenericRoutes;
+use super::structs::{ApiError, ApiTags, BanEntryObject, BanListResponse, BanRequestSchema, BanResponse, IdResponse, IoActionType, IoBatchResponse, IoClientObject, IoClientsResponse, IoClientStateObject, IoClientStateResponse, IoDeliveryStatus, IoHistoryObject, IoHistoryResponse, IoMessageStatus, IoMetricsObject, IoMetricsResponse, IoResponse, IoRoomCloseResponse, IoRoomMembersResponse, IoRoomSendSchema, IoSendSchema, IoSendBatchSchema, IoTokenInfo, IoTokenRequestSchema, IoTokenResponse, MaliciousScriptEntry, ScanMapInfo, ScanMapRequestSchema, ScanMapResponse, ScanMapResult, ScanReportResponse, ScanVerdict, ScanRuleObject, ScanRulesResponse, TimeSyncInfo, TimeSyncResponse, UnbanRequestSchema, WhitelistInfo, WhitelistRequestSchema, WhitelistResponse};
 
 pub struct ApiRoutes {
-    backend: Backend,
-    generic_routes: Arc<GenericRoutes>
+    backend: Arc<Backend>,
+    generic_routes: Arc<GenericRoutes>,
+    scan_limits: ScanLimits,
+    // One per scan allowed to run at once, see `ScanLimits::max_concurrent_scans`
+    scan_permits: Arc<Semaphore>,
+    scan_store: ScanStore,
+    asset_downloader: AssetDownloader,
+    io_key_limiter: RateLimiter,
+    io_target_limiter: RateLimiter,
+    io_history: Arc<IoHistory>
 }
 
 fn unbox_error(box_var: Box<dyn std::error::Error>) -> String {
@@ -17,6 +29,234 @@ fn unbox_error(box_var: Box<dyn std::error::Error>) -> String {
     unboxed
 }
 
+fn malicious_entry(location: &str, (line, column): (usize, usize), rule: &ScanRule, reason: String, target: Option<String>) -> MaliciousScriptEntry {
+    MaliciousScriptEntry {
+        script: location.to_string(),
+        line: line as u64,
+        column: column as u64,
+        reason,
+        target,
+        rule: rule.id.to_string(),
+        suggestion: rule.remediation.to_string(),
+        documentation: rule.documentation()
+    }
+}
+
+fn rule_object(rule: &ScanRule) -> ScanRuleObject {
+    ScanRuleObject {
+        id: rule.id.to_string(),
+        category: rule.category.to_string(),
+        title: rule.title.to_string(),
+        explanation: rule.explanation.to_string(),
+        remediation: rule.remediation.to_string()
+    }
+}
+
+// Longest an IO send is allowed to hold the request open waiting for an ack
+const MAX_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+// Most entries a single history query returns
+const MAX_HISTORY_LIMIT: usize = 1000;
+// Extra time a scan gets past its own deadline to return what it found before we stop waiting for it
+const SCAN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+fn io_delivery_status(status: Option<DeliveryStatus>) -> IoDeliveryStatus {
+    match status {
+        Some(DeliveryStatus::Scheduled) => IoDeliveryStatus::Scheduled,
+        Some(DeliveryStatus::Cancelled) => IoDeliveryStatus::Cancelled,
+        Some(DeliveryStatus::Undelivered) => IoDeliveryStatus::Undelivered,
+        Some(DeliveryStatus::Dropped) => IoDeliveryStatus::Dropped,
+        Some(DeliveryStatus::Sent) => IoDeliveryStatus::Sent,
+        Some(DeliveryStatus::Acknowledged) => IoDeliveryStatus::Acknowledged,
+        None => IoDeliveryStatus::Unknown
+    }
+}
+
+fn io_message_status(id: u64, status: Option<DeliveryStatus>) -> IoMessageStatus {
+    IoMessageStatus { id, status: io_delivery_status(status) }
+}
+
+fn io_action_type(action: &IoAction) -> IoActionType {
+    match action {
+        IoAction::PlayBgm { .. } => IoActionType::PlayBgm,
+        IoAction::StopBgm => IoActionType::StopBgm,
+        IoAction::SyncTime => IoActionType::SyncTime,
+        IoAction::ShowMessage { .. } => IoActionType::ShowMessage,
+        IoAction::Custom { .. } => IoActionType::Custom
+    }
+}
+
+// Unknown action names are already rejected while parsing the body, this checks each action got the fields it needs
+fn io_action(action: IoActionType, bgm: &Option<String>, message: &Option<String>, payload: &Option<serde_json::Value>) -> std::result::Result<IoAction, String> {
+    match action {
+        IoActionType::PlayBgm => match bgm {
+            Some(bgm) => Ok(IoAction::PlayBgm { bgm: bgm.clone() }),
+            None => Err("`play_bgm` needs `bgm` to be set.".to_string())
+        },
+        IoActionType::StopBgm => Ok(IoAction::StopBgm),
+        IoActionType::SyncTime => Ok(IoAction::SyncTime),
+        IoActionType::ShowMessage => match message {
+            Some(message) => Ok(IoAction::ShowMessage { message: message.clone() }),
+            None => Err("`show_message` needs `message` to be set.".to_string())
+        },
+        IoActionType::Custom => match payload {
+            Some(payload) => Ok(IoAction::Custom { payload: payload.clone() }),
+            None => Err("`custom` needs `payload` to be set.".to_string())
+        }
+    }
+}
+
+
+// What a scan found, along with why it stopped early if it did
+struct ScannedMap {
+    result: Vec<MaliciousScriptEntry>,
+    excerpts: Vec<Vec<(usize, String)>>,
+    truncated: Option<String>
+}
+
+// Parses the map and runs every check over its scripts. This is all CPU work, so it runs on a blocking thread.
+// It stops between scripts once `deadline` has passed and returns what it found so far, the caller's timeout
+// only covers parsing the map or a single script taking too long.
+fn scan_map_bytes(backend: &Backend, bytes: Vec<u8>, limits: &ScanLimits, deadline: Instant) -> std::result::Result<ScannedMap, String> {
+    let dom = match backend.dom_from_bytes(bytes) {
+        Ok(dom) => dom,
+        Err(e) => return Err(unbox_error(e))
+    };
+
+    let scripts = backend.dom_find_scripts(&dom);
+    let total_scripts = scripts.len();
+    let mut result: Vec<MaliciousScriptEntry> = Vec::new();
+    let mut excerpts: Vec<Vec<(usize, String)>> = Vec::new();
+    let mut truncated: Option<String> = None;
+    let mut source_bytes: usize = 0;
+    for (index, (location, src)) in scripts.into_iter().enumerate() {
+        if index >= limits.max_scripts {
+            truncated = Some(format!("Only the first {} of {} scripts were scanned.", limits.max_scripts, total_scripts));
+            break;
+        }
+        source_bytes += src.len();
+        if source_bytes > limits.max_source_bytes {
+            truncated = Some(format!("Stopped after {} of {} scripts, the map's source is over the {} byte limit.", index, total_scripts, limits.max_source_bytes));
+            break;
+        }
+        if Instant::now() > deadline {
+            truncated = Some(format!("Stopped after {} of {} scripts, the scan took longer than {} seconds.", index, total_scripts, limits.max_duration.as_secs()));
+            break;
+        }
+
+        let ast = match backend.luau_ast_from_string(&src) {
+            Ok(ast) => ast,
+            Err(e) => return Err(unbox_error(e))
+        };
+        let lookup = LineColLookup::new(&src);
+        let first_finding = result.len();
+        // Every loop below stops adding findings once the scan has as many as it keeps
+        let full = |result: &[MaliciousScriptEntry]| result.len() >= limits.max_findings;
+
+        let found_getfenv = backend.luau_find_global_function_usage(&ast, "getfenv");
+        if !found_getfenv.is_empty() {
+            for ((pos, _), _) in found_getfenv.clone().into_iter() {
+                if full(&result) {
+                    break;
+                }
+                let (line, column) = lookup.get(pos);
+                result.push(malicious_entry(
+                    &location,
+                    (line, column),
+                    &rules::GETFENV_USAGE,
+                    "Detected `getfenv` usage, which is extremely forbidden as it's commonly used for malicious purposes.".to_string(),
+                    None
+                ))
+            }
+        }
+
+        let found_setfenv = backend.luau_find_global_function_usage(&ast, "setfenv");
+        if !found_setfenv.is_empty() {
+            for ((pos, _), _) in found_setfenv.clone().into_iter() {
+                if full(&result) {
+                    break;
+                }
+                let (line, column) = lookup.get(pos);
+                result.push(malicious_entry(
+                    &location,
+                    (line, column),
+                    &rules::SETFENV_USAGE,
+                    "Detected `setfenv` usage, changing the script environment is not allowed.".to_string(),
+                    None
+                ))
+            }
+        }
+
+        let found_require = backend.luau_find_global_function_usage(&ast, "require");
+        if !found_require.is_empty() {
+            for ((pos, _), suffixes) in found_require.clone().into_iter() {
+                if full(&result) {
+                    break;
+                }
+                let first_suffix = suffixes.first().unwrap();
+                if let Suffix::Call(call) = first_suffix {
+                    if let Call::AnonymousCall(args) = call {
+                        if let FunctionArgs::Parentheses { arguments, .. } = args {
+                            if let Some(arg_pair) = arguments.first() {
+                                if let Expression::Number(token) = arg_pair.value() {
+                                    if let Ok(id) = token.token().to_string().parse::<u64>() {
+                                        let (line, column) = lookup.get(pos);
+                                        result.push(malicious_entry(
+                                            &location,
+                                            (line, column),
+                                            &rules::REQUIRE_BY_ID,
+                                            format!("Detected requiring by id ({}). This is used to download malicious scripts, thus is not allowed.", id),
+                                            None
+                                        ))
+                                    }
+                                };
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for found in destructive::find_destructive_operations(&ast).into_iter() {
+            if full(&result) {
+                break;
+            }
+            result.push(malicious_entry(
+                &location,
+                lookup.get(found.position),
+                found.rule,
+                found.reason,
+                found.target
+            ))
+        }
+
+        for found in aliases::find_aliased_globals(&ast).into_iter() {
+            if full(&result) {
+                break;
+            }
+            let (line, column) = lookup.get(found.position);
+            // Skip anything the direct global checks above already reported
+            let duplicate = result[first_finding..].iter().any(|entry| {
+                entry.line == line as u64 && entry.column == column as u64 && entry.rule == found.rule.id
+            });
+            if !duplicate {
+                result.push(malicious_entry(&location, (line, column), found.rule, found.reason, found.target));
+            }
+        }
+
+        let lines: Vec<&str> = src.lines().collect();
+        for entry in result[first_finding..].iter() {
+            excerpts.push(store::excerpt(&lines, entry.line as usize));
+        }
+
+        if full(&result) {
+            truncated = Some(format!("Stopped after {} of {} scripts, the scan reached the limit of {} findings.", index + 1, total_scripts, limits.max_findings));
+            break;
+        }
+    }
+
+    Ok(ScannedMap { result, excerpts, truncated })
+}
+
 #[derive(SecurityScheme)]
 #[oai(
     ty = "api_key",
@@ -27,57 +267,418 @@ pub struct ApiKeyAuthorization(ApiKey);
 
 #[OpenApi]
 impl ApiRoutes {
-    pub fn new(backend: Backend, generic_routes: Arc<GenericRoutes>) -> Self {
-        Self { backend: backend, generic_routes: generic_routes }
+    pub fn new(backend: Arc<Backend>, generic_routes: Arc<GenericRoutes>, scan_limits: ScanLimits, asset_downloader: AssetDownloader) -> Self {
+        let io_config = generic_routes.io_hub.config();
+        let io_key_limiter = RateLimiter::new(io_config.rate_limit_per_key, io_config.rate_limit_window);
+        let io_target_limiter = RateLimiter::new(io_config.rate_limit_per_target, io_config.rate_limit_window);
+        let io_history = Arc::new(IoHistory::new(generic_routes.io_hub.bus(), io_config.history_size));
+        let scan_permits = Arc::new(Semaphore::new(scan_limits.max_concurrent_scans.max(1)));
+        Self { backend: backend, generic_routes: generic_routes, scan_limits: scan_limits, scan_permits, scan_store: ScanStore::new(), asset_downloader, io_key_limiter, io_target_limiter, io_history }
     }
 
-    pub async fn authorized(&self, api_key: ApiKey) -> bool {
-        let valid = self.backend.is_valid_api_key(api_key.key.as_str()).await;
-        match valid {
-            Ok(valid) => {
-                return valid
+    fn scan_response(&self, asset_id: u64, result: Vec<MaliciousScriptEntry>, excerpts: Vec<Vec<(usize, String)>>, truncated: Option<String>) -> ScanMapResponse {
+        let findings = result.iter().zip(excerpts).map(|(entry, excerpt)| StoredFinding::new(entry, excerpt)).collect();
+        let scan_id = self.scan_store.insert(asset_id, findings, truncated.clone());
+        // A scan that was cut short never counts as clean, whatever is left could be where the backdoor is
+        let verdict = match (result.is_empty(), &truncated) {
+            (false, _) => ScanVerdict::Malicious,
+            (true, Some(_)) => ScanVerdict::Incomplete,
+            (true, None) => ScanVerdict::Clean
+        };
+
+        ScanMapResponse::Ok(Json(
+            ScanMapInfo {
+                report: format!("/v1/maptest/scan/{}/report", scan_id),
+                scan_id,
+                result: ScanMapResult {
+                    is_malicious: verdict != ScanVerdict::Clean,
+                    verdict,
+                    scripts: result,
+                    truncated
+                }
+            }
+        ))
+    }
+
+    async fn io_deliver(&self, api_key: &str, message: WebsocketIoStruct, schedule: Option<bool>, ack_timeout_ms: Option<u64>) -> IoMessageStatus {
+        let status = match (schedule, message.start_time) {
+            (Some(true), Some(start_time)) => self.generic_routes.io_hub.schedule(message.clone(), start_time),
+            _ => self.generic_routes.io_hub.send(message.clone())
+        };
+        self.io_report(api_key, message, status, ack_timeout_ms).await
+    }
+
+    // Records a message in the history, then keeps its status there up to date for as long as the hub tracks it.
+    // Runs in the background, sends don't wait on the broker for it.
+    fn io_record(&self, api_key: &str, message: WebsocketIoStruct, status: DeliveryStatus) {
+        if !self.io_history.enabled() {
+            return
+        }
+
+        let mut entry = IoHistoryEntry {
+            id: message.id,
+            sent_at: unix_millis(),
+            sender: history::mask_api_key(api_key),
+            client: message.client,
+            action: message.action,
+            start_time: message.start_time,
+            status
+        };
+        let mut statuses = self.generic_routes.io_hub.subscribe_status(entry.id);
+        // It may have moved on already, e.g. an instance holding the connection reported it sent
+        if let Some(statuses) = statuses.as_mut() {
+            entry.status = *statuses.borrow_and_update();
+        }
+        let history = self.io_history.clone();
+        tokio::spawn(async move {
+            if let Err(e) = history.record(&entry).await {
+                println!("IO history could not record message {}: {}", entry.id, e);
+            }
+
+            let Some(mut statuses) = statuses else {
+                return
+            };
+            // Acknowledged and cancelled messages don't change anymore
+            while !matches!(entry.status, DeliveryStatus::Acknowledged | DeliveryStatus::Cancelled) && statuses.changed().await.is_ok() {
+                entry.status = *statuses.borrow_and_update();
+                if let Err(e) = history.record(&entry).await {
+                    println!("IO history could not update message {}: {}", entry.id, e);
+                }
+            }
+        });
+    }
+
+    // Records a message that went out in the history, and waits for the client to acknowledge it if asked to
+    async fn io_report(&self, api_key: &str, message: WebsocketIoStruct, status: DeliveryStatus, ack_timeout_ms: Option<u64>) -> IoMessageStatus {
+        let id = message.id;
+        self.io_record(api_key, message, status);
+
+        // Even when nothing here took it, a connection on another instance may still, or it may be scheduled
+        match ack_timeout_ms {
+            Some(timeout) => {
+                let timeout = Duration::from_millis(timeout).min(MAX_ACK_TIMEOUT);
+                io_message_status(id, self.generic_routes.io_hub.wait_for_ack(id, timeout).await)
             },
-            Err(_) => return false,
+            _ => io_message_status(id, Some(status))
+        }
+    }
+
+    async fn io_deliver_many(&self, api_key: &str, usernames: &[String], action: IoAction, utc_time: Option<u64>, schedule: Option<bool>, ack_timeout_ms: Option<u64>) -> Vec<IoMessageStatus> {
+        let mut deliveries = Vec::new();
+        for username in usernames.iter() {
+            let message = WebsocketIoStruct {
+                id: self.generic_routes.io_hub.next_message_id(),
+                client: username.clone(),
+                action: action.clone(),
+                start_time: utc_time
+            };
+            deliveries.push(self.io_deliver(api_key, message, schedule, ack_timeout_ms));
+        }
+        join_all(deliveries).await
+    }
+
+    // Scheduled messages are held until `utc_time`, so there has to be one. Every instance keeps a sleeping task for each
+    // of them until then, so how far ahead they can be and how many can wait at once are capped.
+    fn io_schedule(&self, schedule: Option<bool>, utc_time: Option<u64>, count: usize) -> std::result::Result<(), String> {
+        if schedule != Some(true) {
+            return Ok(())
+        }
+        let Some(utc_time) = utc_time else {
+            return Err("`schedule` needs `utc_time` to be set.".to_string())
         };
+
+        let config = self.generic_routes.io_hub.config();
+        if Duration::from_millis(utc_time.saturating_sub(unix_millis())) > config.max_schedule_ahead {
+            return Err(format!("`utc_time` can be at most {} seconds ahead.", config.max_schedule_ahead.as_secs()))
+        }
+        let pending = self.generic_routes.io_hub.scheduled_count();
+        if pending + count > config.max_scheduled {
+            return Err(format!("{} messages are already scheduled, no more than {} can wait at once.", pending, config.max_scheduled))
+        }
+        Ok(())
+    }
+
+    // Counts the messages against the API key's and each target's limit, taking nothing unless they all fit.
+    // When one is hit, returns the error and how many seconds to wait before trying again.
+    fn io_rate_limit(&self, api_key: &str, usernames: &[String]) -> std::result::Result<(), (ApiError, u64)> {
+        let retry_after = |wait: Duration| wait.as_secs_f64().ceil().max(1.0) as u64;
+        // Counted before locking, so a big batch doesn't hold up everyone else's sends
+        let mut per_target: HashMap<&str, u32> = HashMap::new();
+        for username in usernames {
+            *per_target.entry(username.as_str()).or_default() += 1;
+        }
+
+        // Always the key's limiter first, so two requests can't end up waiting on each other's lock
+        let mut key_limits = self.io_key_limiter.lock();
+        let mut target_limits = self.io_target_limiter.lock();
+        if let Err(wait) = key_limits.check(api_key, usernames.len() as u32) {
+            return Err((ApiError { error: "Too many IO messages sent with this API key.".to_string() }, retry_after(wait)))
+        }
+        for (username, count) in per_target.iter() {
+            if let Err(wait) = target_limits.check(username, *count) {
+                return Err((ApiError { error: format!("Too many IO messages sent to {}.", username) }, retry_after(wait)))
+            }
+        }
+
+        key_limits.take(api_key, usernames.len() as u32);
+        for (username, count) in per_target {
+            target_limits.take(username, count);
+        }
+        Ok(())
+    }
+
+    pub async fn authorized(&self, api_key: ApiKey) -> bool {
+        self.generic_routes.api_keys.is_valid(api_key.key.as_str()).await
     }
 
     // IO-related
+    #[oai(path = "/websocket/io/token", method = "post")]
+    pub async fn io_token(&self, api_key: ApiKeyAuthorization, body: Json<IoTokenRequestSchema>) -> Result<IoTokenResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoTokenResponse::Unauthorized)
+        }
+
+        // A token that lives for 0 seconds is expired before the client can use it
+        if body.ttl_seconds == Some(0) {
+            return Ok(IoTokenResponse::BadRequest(Json(ApiError { error: "`ttlSeconds` has to be at least 1.".to_string() })))
+        }
+
+        let config = self.generic_routes.io_hub.config();
+        let ttl = body.ttl_seconds.map(Duration::from_secs).unwrap_or(config.token_ttl).min(config.max_token_ttl);
+        match self.generic_routes.io_tokens.issue(&body.username, body.room.clone(), ttl).await {
+            Ok(token) => Ok(IoTokenResponse::Ok(Json(IoTokenInfo { token, expires_in: ttl.as_secs() }))),
+            Err(e) => {
+                println!("IO token could not be issued: {}", e);
+                Ok(IoTokenResponse::Unavailable(Json(ApiError { error: "Tokens can't be issued right now, try again later.".to_string() })))
+            }
+        }
+    }
+
     #[oai(path = "/websocket/io/send", method = "post")]
     pub async fn io_send(&self, api_key: ApiKeyAuthorization, body: Json<IoSendSchema>) -> Result<IoResponse> {
+        let key = api_key.0.key.clone();
         let authorized = self.authorized(api_key.0).await;
         if !authorized {
             return Ok(IoResponse::Unauthorized)
         }
 
-        let mut io_queue_mutex = self.generic_routes.websocket_io_queue.lock().unwrap();
-        (*io_queue_mutex).push(WebsocketIoStruct {
+        let action = match io_action(body.action, &body.bgm, &body.message, &body.payload) {
+            Ok(action) => action,
+            Err(e) => return Ok(IoResponse::BadRequest(Json(ApiError { error: e })))
+        };
+        if let Err(e) = self.io_schedule(body.schedule, body.utc_time, 1) {
+            return Ok(IoResponse::BadRequest(Json(ApiError { error: e })))
+        }
+
+        if let Err((error, retry_after)) = self.io_rate_limit(&key, std::slice::from_ref(&body.username)) {
+            return Ok(IoResponse::TooManyRequests(Json(error), retry_after))
+        }
+
+        let message = WebsocketIoStruct {
+            id: self.generic_routes.io_hub.next_message_id(),
             client: body.username.clone(),
-            action: body.action.clone(),
-            bgm: body.bgm.clone(),
+            action,
             start_time: body.utc_time
-        });
+        };
 
-        Ok(IoResponse::Ok)
+        Ok(IoResponse::Ok(Json(self.io_deliver(&key, message, body.schedule, body.ack_timeout_ms).await)))
     }
 
     #[oai(path = "/websocket/io/send_batch", method = "post")]
-    pub async fn io_send_batch(&self, api_key: ApiKeyAuthorization, body: Json<IoSendBatchSchema>) -> Result<IoResponse> {
+    pub async fn io_send_batch(&self, api_key: ApiKeyAuthorization, body: Json<IoSendBatchSchema>) -> Result<IoBatchResponse> {
+        let key = api_key.0.key.clone();
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoBatchResponse::Unauthorized)
+        }
+
+        let action = match io_action(body.action, &body.bgm, &body.message, &body.payload) {
+            Ok(action) => action,
+            Err(e) => return Ok(IoBatchResponse::BadRequest(Json(ApiError { error: e })))
+        };
+        if let Err(e) = self.io_schedule(body.schedule, body.utc_time, body.usernames.len()) {
+            return Ok(IoBatchResponse::BadRequest(Json(ApiError { error: e })))
+        }
+
+        if let Err((error, retry_after)) = self.io_rate_limit(&key, &body.usernames) {
+            return Ok(IoBatchResponse::TooManyRequests(Json(error), retry_after))
+        }
+
+        let statuses = self.io_deliver_many(&key, &body.usernames, action, body.utc_time, body.schedule, body.ack_timeout_ms).await;
+        Ok(IoBatchResponse::Ok(Json(statuses)))
+    }
+
+    #[oai(path = "/websocket/io/room/send", method = "post")]
+    pub async fn io_room_send(&self, api_key: ApiKeyAuthorization, body: Json<IoRoomSendSchema>) -> Result<IoBatchResponse> {
+        let key = api_key.0.key.clone();
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoBatchResponse::Unauthorized)
+        }
+
+        let action = match io_action(body.action, &body.bgm, &body.message, &body.payload) {
+            Ok(action) => action,
+            Err(e) => return Ok(IoBatchResponse::BadRequest(Json(ApiError { error: e })))
+        };
+
+        // Every member gets a message of their own, like a batch send, wherever they're connected
+        let members = self.generic_routes.io_hub.room_members(&body.room);
+        if let Err(e) = self.io_schedule(body.schedule, body.utc_time, members.len()) {
+            return Ok(IoBatchResponse::BadRequest(Json(ApiError { error: e })))
+        }
+        if let Err((error, retry_after)) = self.io_rate_limit(&key, &members) {
+            return Ok(IoBatchResponse::TooManyRequests(Json(error), retry_after))
+        }
+        let statuses = self.io_deliver_many(&key, &members, action, body.utc_time, body.schedule, body.ack_timeout_ms).await;
+        Ok(IoBatchResponse::Ok(Json(statuses)))
+    }
+
+    #[oai(path = "/websocket/io/room/members", method = "get")]
+    pub async fn io_room_members(&self, api_key: ApiKeyAuthorization, room: Query<String>) -> Result<IoRoomMembersResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoRoomMembersResponse::Unauthorized)
+        }
+
+        Ok(IoRoomMembersResponse::Ok(Json(self.generic_routes.io_hub.room_members(&room.0))))
+    }
+
+    #[oai(path = "/websocket/io/room/close", method = "post")]
+    pub async fn io_room_close(&self, api_key: ApiKeyAuthorization, room: Query<String>) -> Result<IoRoomCloseResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoRoomCloseResponse::Unauthorized)
+        }
+
+        match self.generic_routes.io_hub.close_room(&room.0) {
+            0 => Ok(IoRoomCloseResponse::NotFound(Json(ApiError { error: format!("Nobody is in room {}.", room.0) }))),
+            _ => Ok(IoRoomCloseResponse::Ok)
+        }
+    }
+
+    #[oai(path = "/websocket/io/cancel", method = "post")]
+    pub async fn io_cancel(&self, api_key: ApiKeyAuthorization, id: Query<u64>) -> Result<IoResponse> {
         let authorized = self.authorized(api_key.0).await;
         if !authorized {
             return Ok(IoResponse::Unauthorized)
         }
 
-        let mut io_queue_mutex = self.generic_routes.websocket_io_queue.lock().unwrap();
-        for username in body.usernames.iter() {
-            (*io_queue_mutex).push(WebsocketIoStruct {
-                client: username.clone(),
-                action: body.action.clone(),
-                bgm: body.bgm.clone(),
-                start_time: body.utc_time
-            });
+        let io_hub = &self.generic_routes.io_hub;
+        if !io_hub.cancel(id.0) {
+            return Ok(IoResponse::BadRequest(Json(ApiError { error: format!("Message {} is not scheduled, it may have been sent already.", id.0) })))
         }
+        Ok(IoResponse::Ok(Json(io_message_status(id.0, io_hub.status(id.0)))))
+    }
 
-        Ok(IoResponse::Ok)
+    #[oai(path = "/websocket/io/status", method = "get")]
+    pub async fn io_status(&self, api_key: ApiKeyAuthorization, id: Query<Vec<u64>>) -> Result<IoBatchResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoBatchResponse::Unauthorized)
+        }
+
+        let statuses = id.0.into_iter().map(|id| io_message_status(id, self.generic_routes.io_hub.status(id))).collect();
+        Ok(IoBatchResponse::Ok(Json(statuses)))
+    }
+
+
+    #[oai(path = "/websocket/io/clients", method = "get")]
+    pub async fn io_clients(&self, api_key: ApiKeyAuthorization) -> Result<IoClientsResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoClientsResponse::Unauthorized)
+        }
+
+        let clients = self.generic_routes.io_hub.clients().into_iter().map(|client| IoClientObject {
+            username: client.username,
+            connections: client.connections as u64,
+            connected_at: client.connected_at,
+            last_heartbeat: client.last_heartbeat,
+            pending_messages: client.pending_messages as u64,
+            overflowed_messages: client.overflowed_messages
+        }).collect();
+        Ok(IoClientsResponse::Ok(Json(clients)))
+    }
+
+    #[oai(path = "/websocket/io/metrics", method = "get")]
+    pub async fn io_metrics(&self, api_key: ApiKeyAuthorization) -> Result<IoMetricsResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoMetricsResponse::Unauthorized)
+        }
+
+        let io_hub = &self.generic_routes.io_hub;
+        Ok(IoMetricsResponse::Ok(Json(IoMetricsObject {
+            connections: io_hub.clients().iter().map(|client| client.connections as u64).sum(),
+            connection_tasks: io_hub.connection_tasks(),
+            overflowed_messages: io_hub.overflowed_messages()
+        })))
+    }
+
+    // HTTP fallback for the websocket's `time_sync` message, less accurate since HTTP has more overhead per request
+    #[oai(path = "/time/sync", method = "get")]
+    pub async fn time_sync(&self, client_time: Query<Option<u64>>) -> Result<TimeSyncResponse> {
+        let server_receive_time = unix_millis();
+        Ok(TimeSyncResponse::Ok(Json(TimeSyncInfo {
+            client_time: client_time.0,
+            server_receive_time,
+            server_send_time: unix_millis()
+        })))
+    }
+
+    #[oai(path = "/websocket/io/state", method = "get")]
+    pub async fn io_client_state(&self, api_key: ApiKeyAuthorization, username: Query<String>) -> Result<IoClientStateResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoClientStateResponse::Unauthorized)
+        }
+
+        let username = username.0;
+        match self.generic_routes.io_hub.client_state(&username) {
+            Some(state) => Ok(IoClientStateResponse::Ok(Json(IoClientStateObject {
+                username,
+                bgm: state.bgm,
+                bgm_started_at: state.bgm_started_at,
+                playing: state.playing,
+                last_error: state.last_error,
+                last_error_id: state.last_error_id,
+                last_seen: state.last_seen
+            }))),
+            None => Ok(IoClientStateResponse::NotFound(Json(ApiError { error: format!("No IO client state reported for {}, or it is no longer connected.", username) })))
+        }
+    }
+
+
+    #[oai(path = "/websocket/io/history", method = "get")]
+    pub async fn io_history(&self, api_key: ApiKeyAuthorization, username: Query<Option<String>>, from: Query<Option<u64>>, to: Query<Option<u64>>, limit: Query<Option<u64>>) -> Result<IoHistoryResponse> {
+        let authorized = self.authorized(api_key.0).await;
+        if !authorized {
+            return Ok(IoHistoryResponse::Unauthorized)
+        }
+
+        let limit = limit.0.map_or(100, |limit| limit as usize).min(MAX_HISTORY_LIMIT);
+        let entries = match self.io_history.query(username.0.as_deref(), from.0, to.0, limit).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("IO history could not be read: {}", e);
+                return Ok(IoHistoryResponse::Unavailable(Json(ApiError { error: "The history can't be read right now, try again later.".to_string() })))
+            }
+        };
+        let entries = entries.into_iter().map(|entry| IoHistoryObject {
+            id: entry.id,
+            sent_at: entry.sent_at,
+            sender: entry.sender,
+            username: entry.client,
+            action: io_action_type(&entry.action),
+            bgm: match entry.action {
+                IoAction::PlayBgm { bgm } => Some(bgm),
+                _ => None
+            },
+            utc_time: entry.start_time,
+            status: io_delivery_status(Some(entry.status))
+        }).collect();
+        Ok(IoHistoryResponse::Ok(Json(entries)))
     }
 
 
@@ -157,87 +758,58 @@ impl ApiRoutes {
         }
 
         let body = body.0;
-
-        let bytes = match self.backend.download_asset_bytes(body.asset_id).await {
-            Ok(bytes) => bytes,
-            Err(e) => return Ok(ScanMapResponse::ServerError(Json(ApiError { error: unbox_error(e) } )))
+        let limits = self.scan_limits.clone();
+        // Waiting for a turn doesn't count towards the scan's own time limit
+        let permit = match tokio::time::timeout(limits.max_duration, self.scan_permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => permit,
+            _ => return Ok(ScanMapResponse::Busy(Json(ApiError { error: "Too many maps are being scanned right now, try again later.".to_string() })))
         };
-
-        let dom = match self.backend.dom_from_bytes(bytes) {
-            Ok(dom) => dom,
-            Err(e) => return Ok(ScanMapResponse::ServerError(Json(ApiError { error: unbox_error(e) } )))
+        let started = Instant::now();
+        let deadline = started + limits.max_duration;
+        let too_slow = || self.scan_response(body.asset_id, Vec::new(), Vec::new(), Some(format!("Scanning the map took longer than {} seconds.", limits.max_duration.as_secs())));
+
+        let bytes = match tokio::time::timeout(limits.max_duration, self.asset_downloader.download(body.asset_id, limits.max_download_bytes)).await {
+            Ok(Ok(bytes)) => bytes,
+            Ok(Err(DownloadError::TooLarge(size))) => return Ok(self.scan_response(body.asset_id, Vec::new(), Vec::new(), Some(format!("Map is {} bytes, which is over the {} byte scan limit.", size, limits.max_download_bytes)))),
+            Ok(Err(DownloadError::Failed(e))) => return Ok(ScanMapResponse::ServerError(Json(ApiError { error: e }))),
+            Err(_) => return Ok(too_slow())
         };
 
-        let scripts = self.backend.dom_find_scripts(&dom);
-        let mut result: Vec<MaliciousScriptEntry> = Vec::new();
-        for (location, src) in scripts.into_iter() {
-            let ast = match self.backend.luau_ast_from_string(&src) {
-                Ok(ast) => ast,
-                Err(e) => return Ok(ScanMapResponse::ServerError(Json(ApiError { error: unbox_error(e) } )))
-            };
-            let lookup = LineColLookup::new(&src);
-
-            let found_getfenv = self.backend.luau_find_global_function_usage(&ast, "getfenv");
-            if !found_getfenv.is_empty() {
-                for ((pos, _), _) in found_getfenv.clone().into_iter() {
-                    let (line, column) = lookup.get(pos);
-                    result.push(MaliciousScriptEntry {
-                        script: location.clone(),
-                        line: line as u64,
-                        column: column as u64,
-                        reason: "Detected `getfenv` usage, which is extremely forbidden as it's commonly used for malicious purposes.".to_string(),
-                    })
-                }
-            }
+        // The blocking thread can't be stopped from out here, it notices the deadline itself at the next script.
+        // It takes the permit along, so a thread we gave up on still counts as a running scan until it's done.
+        let backend = self.backend.clone();
+        let scan_limits = limits.clone();
+        let scan = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            scan_map_bytes(&backend, bytes, &scan_limits, deadline)
+        });
+        let scanned = match tokio::time::timeout(deadline.saturating_duration_since(Instant::now()) + SCAN_GRACE_PERIOD, scan).await {
+            Ok(Ok(Ok(scanned))) => scanned,
+            Ok(Ok(Err(e))) => return Ok(ScanMapResponse::ServerError(Json(ApiError { error: e }))),
+            Ok(Err(e)) => return Ok(ScanMapResponse::ServerError(Json(ApiError { error: format!("Scan failed: {}", e) }))),
+            Err(_) => return Ok(too_slow())
+        };
 
-            let found_setfenv = self.backend.luau_find_global_function_usage(&ast, "setfenv");
-            if !found_setfenv.is_empty() {
-                for ((pos, _), _) in found_setfenv.clone().into_iter() {
-                    let (line, column) = lookup.get(pos);
-                    result.push(MaliciousScriptEntry {
-                        script: location.clone(),
-                        line: line as u64,
-                        column: column as u64,
-                        reason: "Detected `setfenv` usage, changing the script environment is not allowed.".to_string(),
-                    })
-                }
-            }
+        Ok(self.scan_response(body.asset_id, scanned.result, scanned.excerpts, scanned.truncated))
+    }
 
-            let found_require = self.backend.luau_find_global_function_usage(&ast, "require");
-            if !found_require.is_empty() {
-                for ((pos, _), suffixes) in found_require.clone().into_iter() {
-                    let first_suffix = suffixes.first().unwrap();
-                    if let Suffix::Call(call) = first_suffix {
-                        if let Call::AnonymousCall(args) = call {
-                            if let FunctionArgs::Parentheses { arguments, .. } = args {
-                                if let Some(arg_pair) = arguments.first() {
-                                    if let Expression::Number(token) = arg_pair.value() {
-                                        if let Ok(id) = token.token().to_string().parse::<u64>() {
-                                            let (line, column) = lookup.get(pos);
-                                            result.push(MaliciousScriptEntry {
-                                                script: location.clone(),
-                                                line: line as u64,
-                                                column: column as u64,
-                                                reason: format!("Detected requiring by id ({}). This is used to download malicious scripts, thus is not allowed.", id),
-                                            })
-                                        }
-                                    };
-                                }
-                            }
-                        }
-                    }
-                }
+    #[oai(path = "/maptest/scan/rules", method = "get", tag = ApiTags::MapTestOperation)]
+    pub async fn scan_rules(&self, id: Query<Option<String>>) -> Result<ScanRulesResponse> {
+        match id.0 {
+            None => Ok(ScanRulesResponse::Ok(Json(rules::RULES.iter().map(rule_object).collect()))),
+            Some(id) => match rules::find_rule(&id) {
+                Some(rule) => Ok(ScanRulesResponse::Ok(Json(vec![rule_object(rule)]))),
+                None => Ok(ScanRulesResponse::NotFound(Json(ApiError { error: format!("No scan rule with id `{}`.", id) })))
             }
-        };
+        }
+    }
 
-        Ok(ScanMapResponse::Ok(Json(
-            ScanMapInfo {
-                result: ScanMapResult {
-                    is_malicious: !result.is_empty(),
-                    scripts: result
-                }
-            }
-        )))
+    #[oai(path = "/maptest/scan/:id/report", method = "get", tag = ApiTags::MapTestOperation)]
+    pub async fn scan_report(&self, id: Path<String>) -> Result<ScanReportResponse> {
+        match self.scan_store.with_scan(&id.0, report::render) {
+            Some(html) => Ok(ScanReportResponse::Ok(Html(html))),
+            None => Ok(ScanReportResponse::NotFound(Json(ApiError { error: format!("No stored scan with id `{}`, it may have expired.", id.0) })))
+        }
     }
 
     // Map Test Whitelist
@@ -0,0 +1,13 @@
+use futures_util::future::BoxFuture;
+use liquid_breakout_backend::Backend;
+
+// Checks API keys for every route that needs one, the tests swap in a fake that knows a fixed key
+pub trait ApiKeyValidator: Send + Sync {
+    fn is_valid<'a>(&'a self, api_key: &'a str) -> BoxFuture<'a, bool>;
+}
+
+impl ApiKeyValidator for Backend {
+    fn is_valid<'a>(&'a self, api_key: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(async move { self.is_valid_api_key(api_key).await.unwrap_or(false) })
+    }
+}
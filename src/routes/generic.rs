@@ -1,85 +1,317 @@
-use std::sync::{Arc, Mutex};
-use futures_util::{SinkExt, StreamExt};
-use serde::Serialize;
-use poem::{get, handler, web::{websocket::{Message, WebSocket}, Data, Path}, EndpointExt, IntoResponse, Route};
+use std::sync::Arc;
+use futures_util::{stream, SinkExt, StreamExt};
+use poem::{get, handler, http::{header, HeaderMap, StatusCode}, web::{sse::{Event, SSE}, websocket::{CloseCode, Message, WebSocket, WebSocketStream}, Data, Json, Path, Query}, EndpointExt, IntoResponse, Response, Route};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, time::{interval_at, Instant}};
+use crate::io::{auth::{IoTokens, TokenError}, bus::{IoBus, LocalBus, RedisBus}, config::IoConfig, protocol::{IoClientMessage, IoServerMessage}, IoHub, IoObserverEvent};
+use super::auth::ApiKeyValidator;
 
 #[handler]
 fn index() -> String {
     "Welcome to Liquid Breakout Backend site. Visit /docs for documentation.".to_string()
 }
 
-// Used for sending data to IO clients
-#[derive(Clone, Serialize)]
-pub struct WebsocketIoStruct {
-    pub client: String,
-    pub action: String,
-    pub bgm: Option<String>,
-    pub start_time: Option<u64>
+// Websocket subprotocols IO clients can pick from, plain JSON text frames without one
+const IO_PROTOCOLS: [&str; 2] = ["msgpack", "json"];
+
+// How frames are encoded on an IO connection, both carry the same schema
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IoEncoding {
+    Json,
+    // Binary frames, maps keyed by field name
+    MessagePack
+}
+
+impl IoEncoding {
+    // Same pick poem makes when answering the handshake: the first protocol the client asked for that we know
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let requested = headers.get(header::SEC_WEBSOCKET_PROTOCOL).and_then(|value| value.to_str().ok()).unwrap_or("");
+        match requested.split(',').map(str::trim).find(|protocol| IO_PROTOCOLS.contains(protocol)) {
+            Some("msgpack") => Self::MessagePack,
+            _ => Self::Json
+        }
+    }
+
+    fn encode(self, data: &impl Serialize) -> Message {
+        match self {
+            Self::Json => Message::Text(serde_json::to_string(data).unwrap()),
+            Self::MessagePack => Message::Binary(rmp_serde::to_vec_named(data).unwrap())
+        }
+    }
+
+    // Clients are understood either way, text frames are JSON and binary frames MessagePack
+    fn decode<T: DeserializeOwned>(message: &Message) -> Option<Result<T, String>> {
+        match message {
+            Message::Text(text) => Some(serde_json::from_str(text).map_err(|e| e.to_string())),
+            Message::Binary(bytes) => Some(rmp_serde::from_slice(bytes).map_err(|e| e.to_string())),
+            _ => None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WebsocketQuery {
+    // Issued by /v1/websocket/io/token for this username
+    token: Option<String>,
+    // Id of the last IO message the client saw before reconnecting
+    last_id: Option<u64>,
+    // Room to join, e.g. the JobId of the game server
+    room: Option<String>
+}
+
+// Checks the token a client connects with, the error is the response to give it instead
+async fn authorize_io(io_tokens: &IoTokens, token: Option<&str>, username: &str, room: Option<String>) -> Result<Option<String>, Response> {
+    let Some(token) = token else {
+        return Err(StatusCode::UNAUTHORIZED.into_response())
+    };
+    io_tokens.validate(token, username, room).await.map_err(|e| match e {
+        TokenError::Invalid => StatusCode::UNAUTHORIZED.into_response(),
+        TokenError::Unavailable(e) => {
+            println!("IO token could not be checked: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    })
 }
 
 #[handler]
-fn websocket(
+async fn websocket(
     Path((join_type, username)): Path<(String, String)>,
+    Query(query): Query<WebsocketQuery>,
+    headers: &HeaderMap,
     ws: WebSocket,
-    websocket_io_queue: Data<&Arc<Mutex<Vec<WebsocketIoStruct>>>>,
-    sender: Data<&tokio::sync::broadcast::Sender<String>>
-) -> impl IntoResponse {
-    let websocket_io_queue = websocket_io_queue.clone();
-    let sender = sender.clone();
-    let mut receiver = sender.subscribe();
-
-    ws.on_upgrade(move |socket| async move {
-        let (mut sink, _) = socket.split();
-
-        // This checks for data
-        if join_type == "io" {
-            tokio::spawn(async move {
-                loop {
-                    let mut queue: Vec<WebsocketIoStruct> = Vec::new();
-                    {
-                        let mut io_queue_mutex = websocket_io_queue.lock().unwrap();
-                        (*io_queue_mutex)
-                            .iter()
-                            .position(|e| e.client != username)
-                            .map(|e| {
-                                queue.push((*io_queue_mutex).get(e).unwrap().to_owned());
-                                (*io_queue_mutex).remove(e);
-                            });
-                    }
+    io_hub: Data<&Arc<IoHub>>,
+    io_tokens: Data<&Arc<IoTokens>>,
+    api_keys: Data<&Arc<dyn ApiKeyValidator>>
+) -> Response {
+    let io_hub = io_hub.clone();
 
-                    let last = queue.last();
-                    if let Some(data) = last {
-                        let data = serde_json::to_string(data).unwrap();
-                        if sender.send(data).is_err() {
-                            break;
+    match join_type.as_str() {
+        "io" => {
+            let room = match authorize_io(&io_tokens, query.token.as_deref(), &username, query.room.clone()).await {
+                Ok(room) => room,
+                Err(response) => return response
+            };
+
+            let encoding = IoEncoding::negotiate(headers);
+            ws.protocols(IO_PROTOCOLS)
+                .on_upgrade(move |socket| io_connection(socket, io_hub, username, room, query.last_id, encoding))
+                .into_response()
+        },
+        // `username` only names the observer in the logs, observers see everyone's traffic.
+        // The key only comes in the header, query strings end up in proxy and access logs.
+        "observer" => {
+            let api_key = headers.get("x-api-key").and_then(|value| value.to_str().ok());
+            let authorized = match api_key {
+                Some(api_key) => api_keys.is_valid(api_key).await,
+                None => false
+            };
+            if !authorized {
+                return StatusCode::UNAUTHORIZED.into_response()
+            }
+
+            ws.on_upgrade(move |socket| observer_connection(socket, io_hub, username)).into_response()
+        },
+        _ => ws.on_upgrade(move |mut socket| async move {
+            let reason = format!("Unknown join type {}", join_type);
+            let _ = socket.send(Message::Close(Some((CloseCode::from(4400), reason)))).await;
+        }).into_response()
+    }
+}
+
+// Runs a single IO connection until either side closes it or it goes idle.
+// Everything happens in this one task, so returning from here is all the cleanup there is: the inbox
+// gets dropped (unregistering the connection) and the socket is closed.
+async fn io_connection(socket: WebSocketStream, io_hub: Arc<IoHub>, username: String, room: Option<String>, last_id: Option<u64>, encoding: IoEncoding) {
+    let _task = io_hub.connection_task();
+    let (mut sink, mut stream) = socket.split();
+    let heartbeat_interval = io_hub.config().heartbeat_interval;
+    let idle_timeout = io_hub.config().idle_timeout;
+
+    let Some(inbox) = io_hub.register(&username, room, last_id) else {
+        let _ = sink.send(Message::Close(Some((CloseCode::from(4429), "Too many connections for this username".to_string())))).await;
+        return
+    };
+    let mut heartbeat = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+    let mut last_activity = Instant::now();
+
+    // This waits for data sent to this username and passes it on, the task sleeps while nothing is queued.
+    // Client messages (acks, pings, status reports) come back the other way.
+    loop {
+        tokio::select! {
+            data = inbox.recv() => {
+                if sink.send(encoding.encode(&data)).await.is_err() {
+                    break;
+                }
+            },
+            message = stream.next() => {
+                match message {
+                    Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) => {
+                        last_activity = Instant::now();
+                        inbox.heartbeat();
+                        let reply = match IoEncoding::decode::<IoClientMessage>(&message) {
+                            Some(Ok(client_message)) => io_hub.handle_client_message(&username, client_message),
+                            Some(Err(e)) => Some(IoServerMessage::Error { message: format!("Invalid message: {}", e) }),
+                            None => None
+                        };
+                        if let Some(reply) = reply {
+                            if sink.send(encoding.encode(&reply)).await.is_err() {
+                                break;
+                            }
                         }
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Pongs to our heartbeat, or anything else the client sends, still count as being alive
+                    Some(Ok(_)) => {
+                        last_activity = Instant::now();
+                        inbox.heartbeat();
                     }
                 }
-            });
+            },
+            _ = heartbeat.tick() => {
+                if last_activity.elapsed() > idle_timeout {
+                    let _ = sink.send(Message::Close(Some((CloseCode::Away, "Idle timeout".to_string())))).await;
+                    break;
+                }
+                if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
 
-            // This is basically sending the data to all clients connected (in this case, "sinks")
-            tokio::spawn(async move {
-                while let Ok(msg) = receiver.recv().await {
-                    if sink.send(Message::Text(msg)).await.is_err() {
-                        break;
-                    }
+    drop(inbox);
+    let _ = sink.close().await;
+}
+
+// Mirrors the IO traffic of every instance as JSON, see `IoObserverEvent`.
+// Anything the observer sends is ignored, apart from closing the connection.
+async fn observer_connection(socket: WebSocketStream, io_hub: Arc<IoHub>, name: String) {
+    let _task = io_hub.connection_task();
+    let (mut sink, mut stream) = socket.split();
+    let heartbeat_interval = io_hub.config().heartbeat_interval;
+    let mut events = io_hub.observe();
+    let mut heartbeat = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+    println!("IO observer {} connected", name);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => IoObserverEvent::Lagged { skipped },
+                    Err(RecvError::Closed) => break
+                };
+                let event = serde_json::to_string(&event).unwrap();
+                if sink.send(Message::Text(event)).await.is_err() {
+                    break;
                 }
-            });
+            },
+            message = stream.next() => {
+                if matches!(message, Some(Ok(Message::Close(_))) | Some(Err(_)) | None) {
+                    break;
+                }
+            },
+            _ = heartbeat.tick() => {
+                if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
         }
-    })
+    }
+
+    println!("IO observer {} disconnected", name);
+    let _ = sink.close().await;
+}
+
+// Same stream as the `io` websocket, for clients that can't open one.
+// Every message is an event with its IO message id as the event id, so EventSource resumes through
+// `Last-Event-ID` on its own. Client messages (acks, pings, status reports) go to `sse_io_message`.
+#[handler]
+async fn sse_io(
+    Path(username): Path<String>,
+    Query(query): Query<WebsocketQuery>,
+    headers: &HeaderMap,
+    io_hub: Data<&Arc<IoHub>>,
+    io_tokens: Data<&Arc<IoTokens>>
+) -> Response {
+    let room = match authorize_io(&io_tokens, query.token.as_deref(), &username, query.room.clone()).await {
+        Ok(room) => room,
+        Err(response) => return response
+    };
+
+    let last_event_id = headers.get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let last_id = last_event_id.or(query.last_id);
+
+    // The inbox and task guard live inside the stream, so the connection unregisters and stops counting as a task
+    // once the client goes away and poem drops the response
+    let Some(inbox) = io_hub.register(&username, room, last_id) else {
+        return StatusCode::TOO_MANY_REQUESTS.into_response()
+    };
+    let task = io_hub.connection_task();
+    let heartbeat_interval = io_hub.config().heartbeat_interval;
+    let heartbeat = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+    let events = stream::unfold((inbox, heartbeat, task), |(inbox, mut heartbeat, task)| async move {
+        loop {
+            tokio::select! {
+                data = inbox.recv() => {
+                    inbox.heartbeat();
+                    let event = Event::message(serde_json::to_string(&data).unwrap()).id(data.id.to_string());
+                    return Some((event, (inbox, heartbeat, task)))
+                },
+                // poem writes the keep-alive comments itself and drops the stream once one fails,
+                // so the client is still there for as long as this keeps getting polled
+                _ = heartbeat.tick() => inbox.heartbeat()
+            }
+        }
+    });
+
+    SSE::new(events).keep_alive(heartbeat_interval).into_response()
+}
+
+#[handler]
+async fn sse_io_message(
+    Path(username): Path<String>,
+    Query(query): Query<WebsocketQuery>,
+    body: String,
+    io_hub: Data<&Arc<IoHub>>,
+    io_tokens: Data<&Arc<IoTokens>>
+) -> Response {
+    if let Err(response) = authorize_io(&io_tokens, query.token.as_deref(), &username, None).await {
+        return response
+    }
+
+    let reply = match serde_json::from_str::<IoClientMessage>(&body) {
+        Ok(client_message) => io_hub.handle_client_message(&username, client_message),
+        Err(e) => Some(IoServerMessage::Error { message: format!("Invalid message: {}", e) })
+    };
+    match reply {
+        Some(reply) => Json(reply).into_response(),
+        None => StatusCode::NO_CONTENT.into_response()
+    }
 }
 
 pub struct GenericRoutes {
-    pub websocket_io_queue: Arc<Mutex<Vec<WebsocketIoStruct>>>
+    pub io_hub: Arc<IoHub>,
+    pub io_tokens: Arc<IoTokens>,
+    // The backend outside of tests
+    pub api_keys: Arc<dyn ApiKeyValidator>
 }
 impl GenericRoutes {
-    pub fn new() -> Self {
-        Self { websocket_io_queue: Arc::new(Mutex::new(Vec::new())) }
+    pub async fn new(io_config: IoConfig, api_keys: Arc<dyn ApiKeyValidator>) -> Self {
+        let bus: Arc<dyn IoBus> = match &io_config.bus_url {
+            Some(url) => Arc::new(RedisBus::connect(url).await),
+            None => Arc::new(LocalBus::new())
+        };
+        let io_tokens = Arc::new(IoTokens::new(bus.clone()));
+        Self { io_hub: IoHub::new(io_config, bus), io_tokens, api_keys }
     }
 
     pub fn collect(&self) -> Route {
         Route::new()
             .at("/", get(index))
-            .at("/websocket/:join_type/:username", websocket.data(self.websocket_io_queue.clone()).data(tokio::sync::broadcast::channel::<String>(1).0))
+            .at("/websocket/:join_type/:username", websocket.data(self.io_hub.clone()).data(self.io_tokens.clone()).data(self.api_keys.clone()))
+            .at("/sse/io/:username", get(sse_io).post(sse_io_message).data(self.io_hub.clone()).data(self.io_tokens.clone()))
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,8 @@
 pub mod apis;
+pub mod auth;
 pub mod generic;
 
-mod structs;
\ No newline at end of file
+pub(crate) mod structs;
+
+#[cfg(test)]
+mod tests;
\ No newline at end of file
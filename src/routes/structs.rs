@@ -1,4 +1,4 @@
-use poem_openapi::{payload::Json, payload::PlainText, ApiResponse, Object, Tags};
+use poem_openapi::{payload::Html, payload::Json, payload::PlainText, ApiResponse, Enum, Object, Tags};
 
 fn default_user_id() -> i64 {
     1
@@ -16,20 +16,68 @@ fn default_reason() -> String {
 }
 
 // Request schemas
+#[derive(Debug, Enum, Clone, Copy, Eq, PartialEq)]
+#[oai(rename_all = "snake_case")]
+pub enum IoActionType {
+    // Needs `bgm`
+    PlayBgm,
+    StopBgm,
+    SyncTime,
+    // Needs `message`
+    ShowMessage,
+    // Needs `payload`, which is passed to the client as is
+    Custom
+}
+
 #[derive(Debug, Object, Clone, Eq, PartialEq)]
 pub struct IoSendSchema {
     pub username: String,
-    pub action: String,
+    pub action: IoActionType,
     pub bgm: Option<String>,
+    pub message: Option<String>,
+    pub payload: Option<serde_json::Value>,
+    // Unix time in milliseconds the client should start the action at
     pub utc_time: Option<u64>,
+    // Hold the message on the server and only send it once `utc_time` is reached
+    pub schedule: Option<bool>,
+    // Wait up to this long for the client to acknowledge the message before responding
+    #[oai(rename = "ackTimeoutMs")]
+    pub ack_timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Object, Clone, Eq, PartialEq)]
 pub struct IoSendBatchSchema {
     pub usernames: Vec<String>,
-    pub action: String,
+    pub action: IoActionType,
     pub bgm: Option<String>,
+    pub message: Option<String>,
+    pub payload: Option<serde_json::Value>,
     pub utc_time: Option<u64>,
+    pub schedule: Option<bool>,
+    #[oai(rename = "ackTimeoutMs")]
+    pub ack_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Object, Clone, Eq, PartialEq)]
+pub struct IoRoomSendSchema {
+    pub room: String,
+    pub action: IoActionType,
+    pub bgm: Option<String>,
+    pub message: Option<String>,
+    pub payload: Option<serde_json::Value>,
+    pub utc_time: Option<u64>,
+    pub schedule: Option<bool>,
+    #[oai(rename = "ackTimeoutMs")]
+    pub ack_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Object, Clone, Eq, PartialEq)]
+pub struct IoTokenRequestSchema {
+    pub username: String,
+    // Lock the token to this room
+    pub room: Option<String>,
+    #[oai(rename = "ttlSeconds")]
+    pub ttl_seconds: Option<u64>,
 }
 
 #[derive(Debug, Object, Clone, Eq, PartialEq)]
@@ -90,15 +138,222 @@ pub struct ApiError {
 
 // API-specifics
 
+#[derive(Enum)]
+#[oai(rename_all = "snake_case")]
+pub enum IoDeliveryStatus {
+    Scheduled,
+    Cancelled,
+    Undelivered,
+    // Turned away because the target's queues were full
+    Dropped,
+    Sent,
+    Acknowledged,
+    // The id was never sent, or is too old to still be tracked
+    Unknown
+}
+
+#[derive(Object)]
+pub struct IoMessageStatus {
+    pub id: u64,
+    pub status: IoDeliveryStatus
+}
+
 #[derive(ApiResponse)]
 pub enum IoResponse {
     #[oai(status = 200)]
-    Ok,
+    Ok(Json<IoMessageStatus>),
+
+    #[oai(status = 400)]
+    BadRequest(Json<ApiError>),
+
+    #[oai(status = 401)]
+    Unauthorized,
+
+    // Seconds to wait in `Retry-After`
+    #[oai(status = 429)]
+    TooManyRequests(Json<ApiError>, #[oai(header = "Retry-After")] u64)
+}
+
+#[derive(Object)]
+pub struct IoTokenInfo {
+    // Pass as `?token=` when connecting to /websocket/io/:username
+    pub token: String,
+    #[oai(rename = "expiresIn")]
+    pub expires_in: u64
+}
+
+#[derive(ApiResponse)]
+pub enum IoTokenResponse {
+    #[oai(status = 200)]
+    Ok(Json<IoTokenInfo>),
+
+    #[oai(status = 400)]
+    BadRequest(Json<ApiError>),
+
+    #[oai(status = 401)]
+    Unauthorized,
+
+    // The broker holding the tokens couldn't be reached
+    #[oai(status = 503)]
+    Unavailable(Json<ApiError>)
+}
+
+#[derive(Object)]
+pub struct IoClientObject {
+    pub username: String,
+    pub connections: u64,
+    // Unix times in milliseconds
+    #[oai(rename = "connectedAt")]
+    pub connected_at: u64,
+    #[oai(rename = "lastHeartbeat")]
+    pub last_heartbeat: u64,
+    // Messages queued for its connections that haven't gone out over the socket yet
+    #[oai(rename = "pendingMessages")]
+    pub pending_messages: u64,
+    // Messages lost because the client wasn't reading fast enough
+    #[oai(rename = "overflowedMessages")]
+    pub overflowed_messages: u64
+}
+
+#[derive(ApiResponse)]
+pub enum IoClientsResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<IoClientObject>>),
+
+    #[oai(status = 401)]
+    Unauthorized
+}
+
+#[derive(Object)]
+pub struct IoMetricsObject {
+    pub connections: u64,
+    // IO websocket tasks, SSE streams and observer websockets still running. Staying above `connections` for long
+    // while no observers are connected means some are stuck closing.
+    #[oai(rename = "connectionTasks")]
+    pub connection_tasks: u64,
+    // Since startup, over all connections
+    #[oai(rename = "overflowedMessages")]
+    pub overflowed_messages: u64
+}
+
+#[derive(ApiResponse)]
+pub enum IoMetricsResponse {
+    #[oai(status = 200)]
+    Ok(Json<IoMetricsObject>),
 
     #[oai(status = 401)]
     Unauthorized
 }
 
+#[derive(Object)]
+pub struct IoClientStateObject {
+    pub username: String,
+    pub bgm: Option<String>,
+    #[oai(rename = "bgmStartedAt")]
+    pub bgm_started_at: Option<u64>,
+    pub playing: Option<bool>,
+    #[oai(rename = "lastError")]
+    pub last_error: Option<String>,
+    #[oai(rename = "lastErrorId")]
+    pub last_error_id: Option<u64>,
+    #[oai(rename = "lastSeen")]
+    pub last_seen: u64
+}
+
+#[derive(ApiResponse)]
+pub enum IoClientStateResponse {
+    #[oai(status = 200)]
+    Ok(Json<IoClientStateObject>),
+
+    #[oai(status = 401)]
+    Unauthorized,
+
+    #[oai(status = 404)]
+    NotFound(Json<ApiError>)
+}
+
+#[derive(Object)]
+pub struct IoHistoryObject {
+    pub id: u64,
+    // Unix times in milliseconds
+    #[oai(rename = "sentAt")]
+    pub sent_at: u64,
+    // Last few characters of the sender's API key
+    pub sender: String,
+    pub username: String,
+    pub action: IoActionType,
+    pub bgm: Option<String>,
+    #[oai(rename = "utcTime")]
+    pub utc_time: Option<u64>,
+    pub status: IoDeliveryStatus
+}
+
+#[derive(ApiResponse)]
+pub enum IoHistoryResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<IoHistoryObject>>),
+
+    #[oai(status = 401)]
+    Unauthorized,
+
+    // The broker holding the history couldn't be reached
+    #[oai(status = 503)]
+    Unavailable(Json<ApiError>)
+}
+
+#[derive(ApiResponse)]
+pub enum IoRoomMembersResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<String>>),
+
+    #[oai(status = 401)]
+    Unauthorized
+}
+
+#[derive(ApiResponse)]
+pub enum IoRoomCloseResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 401)]
+    Unauthorized,
+
+    #[oai(status = 404)]
+    NotFound(Json<ApiError>)
+}
+
+#[derive(ApiResponse)]
+pub enum IoBatchResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<IoMessageStatus>>),
+
+    #[oai(status = 400)]
+    BadRequest(Json<ApiError>),
+
+    #[oai(status = 401)]
+    Unauthorized,
+
+    #[oai(status = 429)]
+    TooManyRequests(Json<ApiError>, #[oai(header = "Retry-After")] u64)
+}
+
+// Clock sync, see `IoServerMessage::TimeSync` for how to use it
+#[derive(Object)]
+pub struct TimeSyncInfo {
+    #[oai(rename = "clientTime")]
+    pub client_time: Option<u64>,
+    #[oai(rename = "serverReceiveTime")]
+    pub server_receive_time: u64,
+    #[oai(rename = "serverSendTime")]
+    pub server_send_time: u64
+}
+
+#[derive(ApiResponse)]
+pub enum TimeSyncResponse {
+    #[oai(status = 200)]
+    Ok(Json<TimeSyncInfo>)
+}
+
 // Moderation's Ban List
 #[derive(Object)]
 pub struct BanEntryObject {
@@ -172,8 +427,11 @@ fn default_line_col() -> u64 {
 fn default_malicious_reason() -> String {
     "Used forbidden function".to_string()
 }
+fn default_rule_id() -> String {
+    "getfenv-usage".to_string()
+}
 
-#[derive(Object)]
+#[derive(Object, Clone)]
 pub struct MaliciousScriptEntry {
     #[oai(default = "default_script")]
     pub script: String,
@@ -182,22 +440,45 @@ pub struct MaliciousScriptEntry {
     #[oai(default = "default_line_col")]
     pub column: u64,
     #[oai(default = "default_malicious_reason")]
-    pub reason: String
+    pub reason: String,
+    // The service a destructive operation was aimed at, if any
+    pub target: Option<String>,
+    #[oai(default = "default_rule_id")]
+    pub rule: String,
+    pub suggestion: String,
+    pub documentation: String
 }
 
 fn default_malicious_result() -> bool {
     false
 }
 
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[oai(rename_all = "snake_case")]
+pub enum ScanVerdict {
+    Clean,
+    Malicious,
+    // A scan limit was hit before anything was found, the part that wasn't scanned could still hide something
+    Incomplete
+}
+
 #[derive(Object)]
 pub struct ScanMapResult {
+    // Also set for incomplete scans, so clients that only read this one reject maps that couldn't be fully scanned
     #[oai(default = "default_malicious_result", rename = "isMalicious")]
     pub is_malicious: bool,
-    pub scripts: Vec<MaliciousScriptEntry>
+    pub verdict: ScanVerdict,
+    pub scripts: Vec<MaliciousScriptEntry>,
+    // Set when a scan limit was hit, the findings only cover what got scanned before that
+    pub truncated: Option<String>
 }
 
 #[derive(Object)]
 pub struct ScanMapInfo {
+    #[oai(rename = "scanId")]
+    pub scan_id: String,
+    // Link to the HTML report for this scan
+    pub report: String,
     pub result: ScanMapResult
 }
 
@@ -213,7 +494,41 @@ pub enum ScanMapResponse {
     Unauthorized,
 
     #[oai(status = 500)]
-    ServerError(Json<ApiError>)
+    ServerError(Json<ApiError>),
+
+    // Too many scans are running already
+    #[oai(status = 503)]
+    Busy(Json<ApiError>)
+}
+
+// Map Test's Scan Report
+#[derive(ApiResponse)]
+pub enum ScanReportResponse {
+    #[oai(status = 200)]
+    Ok(Html<String>),
+
+    #[oai(status = 404)]
+    NotFound(Json<ApiError>)
+}
+
+// Map Test's Scan Rules
+#[derive(Object)]
+pub struct ScanRuleObject {
+    #[oai(default = "default_rule_id")]
+    pub id: String,
+    pub category: String,
+    pub title: String,
+    pub explanation: String,
+    pub remediation: String
+}
+
+#[derive(ApiResponse)]
+pub enum ScanRulesResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<ScanRuleObject>>),
+
+    #[oai(status = 404)]
+    NotFound(Json<ApiError>)
 }
 
 // Map Test's ID system routes
@@ -0,0 +1,566 @@
+// Boots the routes in-process with a fake API key check and talks to them the way game servers and IO clients do,
+// HTTP requests for the API and real websockets for the clients, both over a local port.
+use std::{sync::Arc, time::Duration};
+use futures_util::{future::BoxFuture, SinkExt, StreamExt};
+use liquid_breakout_backend::Backend;
+use poem::{listener::{Acceptor, Listener, TcpListener}, Route, Server};
+use poem_openapi::OpenApiService;
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::{client::IntoClientRequest, Message}, MaybeTlsStream, WebSocketStream};
+use crate::{io::{config::IoConfig, unix_millis}, scanner::{download::AssetDownloader, limits::ScanLimits}};
+use super::{apis::ApiRoutes, auth::ApiKeyValidator, generic::GenericRoutes};
+
+const API_KEY: &str = "test-api-key";
+// How long to wait for something that should arrive, and to make sure something that shouldn't doesn't
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+const SILENCE_TIMEOUT: Duration = Duration::from_millis(200);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct FakeApiKeys;
+
+impl ApiKeyValidator for FakeApiKeys {
+    fn is_valid<'a>(&'a self, api_key: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(async move { api_key == API_KEY })
+    }
+}
+
+struct Harness {
+    host: String,
+    client: reqwest::Client,
+    generic_routes: Arc<GenericRoutes>
+}
+
+impl Harness {
+    async fn start() -> Self {
+        Self::start_with(IoConfig::default()).await
+    }
+
+    async fn start_with(io_config: IoConfig) -> Self {
+        let generic_routes = Arc::new(GenericRoutes::new(io_config, Arc::new(FakeApiKeys)).await);
+        // Only reached through routes that check keys with the fake, so it never needs a database
+        let backend = Arc::new(Backend::new(String::new(), vec![String::new(), String::new()]));
+        let api_routes = ApiRoutes::new(backend, generic_routes.clone(), ScanLimits::default(), AssetDownloader::new(String::new()));
+        let app = Route::new()
+            .nest("/", generic_routes.collect())
+            .nest("/v1", OpenApiService::new(api_routes, "Liquid Breakout API", "test"));
+
+        let acceptor = TcpListener::bind("127.0.0.1:0").into_acceptor().await.unwrap();
+        let host = acceptor.local_addr()[0].as_socket_addr().unwrap().to_string();
+        tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+
+        Self { host, client: reqwest::Client::new(), generic_routes }
+    }
+
+    async fn request(&self, path: &str, api_key: &str, body: Value) -> reqwest::Response {
+        self.client.post(format!("http://{}{}", self.host, path))
+            .header("x-api-key", api_key)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap()
+    }
+
+    // Responses without a JSON body, like a plain 401, come back as null
+    async fn post(&self, path: &str, api_key: &str, body: Value) -> (u16, Value) {
+        let response = self.request(path, api_key, body).await;
+        let status = response.status().as_u16();
+        let body = serde_json::from_str(&response.text().await.unwrap()).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    async fn token(&self, username: &str) -> String {
+        let (status, body) = self.post("/v1/websocket/io/token", API_KEY, json!({ "username": username })).await;
+        assert_eq!(status, 200);
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    async fn connect(&self, username: &str) -> Socket {
+        let token = self.token(username).await;
+        let (socket, _) = connect_async(format!("ws://{}/websocket/io/{}?token={}", self.host, username, token)).await.unwrap();
+        socket
+    }
+
+    async fn connect_msgpack(&self, username: &str) -> Socket {
+        let token = self.token(username).await;
+        let mut request = format!("ws://{}/websocket/io/{}?token={}", self.host, username, token).into_client_request().unwrap();
+        request.headers_mut().insert("sec-websocket-protocol", "msgpack".parse().unwrap());
+        let (socket, response) = connect_async(request).await.unwrap();
+        assert_eq!(response.headers()["sec-websocket-protocol"], "msgpack");
+        socket
+    }
+
+    async fn observe(&self) -> Socket {
+        let mut request = format!("ws://{}/websocket/observer/dashboard", self.host).into_client_request().unwrap();
+        request.headers_mut().insert("x-api-key", API_KEY.parse().unwrap());
+        let (socket, _) = connect_async(request).await.unwrap();
+        socket
+    }
+
+    async fn open_sse(&self, username: &str, last_event_id: Option<&str>) -> SseStream {
+        let token = self.token(username).await;
+        let mut request = self.client.get(format!("http://{}/sse/io/{}?token={}", self.host, username, token));
+        if let Some(last_event_id) = last_event_id {
+            request = request.header("Last-Event-ID", last_event_id);
+        }
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        SseStream { response, buffer: String::new() }
+    }
+
+    // What SSE clients send back instead of websocket frames, returns the status code
+    async fn sse_message(&self, username: &str, message: Value) -> u16 {
+        let token = self.token(username).await;
+        let response = self.client.post(format!("http://{}/sse/io/{}?token={}", self.host, username, token))
+            .body(message.to_string())
+            .send()
+            .await
+            .unwrap();
+        response.status().as_u16()
+    }
+
+    fn connected(&self) -> usize {
+        self.generic_routes.io_hub.clients().iter().map(|client| client.connections).sum()
+    }
+
+    // Connections unregister from their own task once the socket closes, so give them a moment
+    async fn wait_until_connected(&self, expected: usize) {
+        let waited = timeout(RECEIVE_TIMEOUT, async {
+            while self.connected() != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
+        assert!(waited.is_ok(), "expected {} connections, still have {}", expected, self.connected());
+    }
+
+    fn connection_tasks(&self) -> u64 {
+        self.generic_routes.io_hub.connection_tasks()
+    }
+
+    // Unlike the connections, this only goes down once the task has closed its socket and returned
+    async fn wait_until_tasks(&self, expected: u64) {
+        let waited = timeout(RECEIVE_TIMEOUT, async {
+            while self.connection_tasks() != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
+        assert!(waited.is_ok(), "expected {} connection tasks, still have {}", expected, self.connection_tasks());
+    }
+}
+
+// An open `/sse/io` stream, read one event at a time
+struct SseStream {
+    response: reqwest::Response,
+    buffer: String
+}
+
+impl SseStream {
+    // (event id, data) of the next message, keep-alive comments are skipped
+    async fn next(&mut self) -> (String, Value) {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let event: String = self.buffer.drain(..end + 2).collect();
+                let mut id = String::new();
+                let mut data = None;
+                for line in event.lines() {
+                    if let Some(value) = line.strip_prefix("id:") {
+                        id = value.trim().to_string();
+                    } else if let Some(value) = line.strip_prefix("data:") {
+                        data = Some(value.trim().to_string());
+                    }
+                }
+                match data {
+                    Some(data) => return (id, serde_json::from_str(&data).unwrap()),
+                    None => continue
+                }
+            }
+
+            let chunk = timeout(RECEIVE_TIMEOUT, self.response.chunk()).await.expect("nothing received").unwrap().expect("stream ended");
+            self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+}
+
+// SSE streams only notice the client is gone when a keep-alive fails to go out, so the SSE tests have them tick fast
+fn fast_heartbeat() -> IoConfig {
+    IoConfig { heartbeat_interval: Duration::from_millis(50), ..IoConfig::default() }
+}
+
+async fn receive(socket: &mut Socket) -> Value {
+    loop {
+        let message = timeout(RECEIVE_TIMEOUT, socket.next()).await.expect("nothing received").unwrap().unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap()
+        }
+    }
+}
+
+async fn receive_msgpack(socket: &mut Socket) -> Value {
+    loop {
+        match timeout(RECEIVE_TIMEOUT, socket.next()).await.expect("nothing received").unwrap().unwrap() {
+            Message::Binary(bytes) => return rmp_serde::from_slice(&bytes).unwrap(),
+            Message::Text(text) => panic!("text frame on a msgpack connection: {}", text),
+            _ => continue
+        }
+    }
+}
+
+// Code of the close frame the server ends the connection with
+async fn close_code(socket: &mut Socket) -> u16 {
+    loop {
+        match timeout(RECEIVE_TIMEOUT, socket.next()).await.expect("not closed").unwrap().unwrap() {
+            Message::Close(frame) => return frame.map(|frame| u16::from(frame.code)).unwrap_or(0),
+            _ => continue
+        }
+    }
+}
+
+async fn assert_silent(socket: &mut Socket) {
+    while let Ok(message) = timeout(SILENCE_TIMEOUT, socket.next()).await {
+        if let Some(Ok(Message::Text(text))) = message {
+            panic!("unexpected message: {}", text);
+        }
+    }
+}
+
+fn play_bgm(username: &str, bgm: &str) -> Value {
+    json!({ "username": username, "action": "play_bgm", "bgm": bgm })
+}
+
+#[tokio::test]
+async fn send_only_reaches_its_target() {
+    let harness = Harness::start().await;
+    let mut alice = harness.connect("alice").await;
+    let mut bob = harness.connect("bob").await;
+    harness.wait_until_connected(2).await;
+
+    let (status, body) = harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "theme")).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "sent");
+
+    let message = receive(&mut alice).await;
+    assert_eq!(message["client"], "alice");
+    assert_eq!(message["action"], "play_bgm");
+    assert_eq!(message["bgm"], "theme");
+    assert_eq!(message["id"], body["id"]);
+    assert_silent(&mut bob).await;
+}
+
+#[tokio::test]
+async fn send_reaches_every_connection_of_the_target() {
+    let harness = Harness::start().await;
+    let mut first = harness.connect("alice").await;
+    let mut second = harness.connect("alice").await;
+    harness.wait_until_connected(2).await;
+
+    harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "theme")).await;
+    assert_eq!(receive(&mut first).await["bgm"], "theme");
+    assert_eq!(receive(&mut second).await["bgm"], "theme");
+}
+
+#[tokio::test]
+async fn batch_send_reaches_every_listed_username() {
+    let harness = Harness::start().await;
+    let mut alice = harness.connect("alice").await;
+    let mut bob = harness.connect("bob").await;
+    let mut carol = harness.connect("carol").await;
+    harness.wait_until_connected(3).await;
+
+    let body = json!({ "usernames": ["alice", "bob", "dave"], "action": "stop_bgm" });
+    let (status, body) = harness.post("/v1/websocket/io/send_batch", API_KEY, body).await;
+    assert_eq!(status, 200);
+    let statuses: Vec<&str> = body.as_array().unwrap().iter().map(|status| status["status"].as_str().unwrap()).collect();
+    assert_eq!(statuses, ["sent", "sent", "undelivered"]);
+
+    assert_eq!(receive(&mut alice).await["action"], "stop_bgm");
+    assert_eq!(receive(&mut bob).await["action"], "stop_bgm");
+    assert_silent(&mut carol).await;
+}
+
+#[tokio::test]
+async fn disconnected_clients_are_unregistered() {
+    let harness = Harness::start().await;
+    let mut sockets = Vec::new();
+    for index in 0..20 {
+        sockets.push(harness.connect(&format!("player{}", index)).await);
+    }
+    harness.wait_until_connected(20).await;
+
+    // Half leave properly, the other half just drop the connection
+    for (index, mut socket) in sockets.into_iter().enumerate() {
+        if index % 2 == 0 {
+            socket.close(None).await.unwrap();
+        }
+    }
+    harness.wait_until_connected(0).await;
+
+    let (_, body) = harness.post("/v1/websocket/io/send", API_KEY, play_bgm("player0", "theme")).await;
+    assert_eq!(body["status"], "undelivered");
+}
+
+#[tokio::test]
+async fn connection_tasks_finish_after_disconnecting() {
+    let harness = Harness::start().await;
+    let mut alice = harness.connect("alice").await;
+    let bob = harness.connect("bob").await;
+    harness.wait_until_connected(2).await;
+    assert_eq!(harness.connection_tasks(), 2);
+
+    alice.close(None).await.unwrap();
+    harness.wait_until_tasks(1).await;
+    // No close frame this time, the TCP connection just goes away
+    drop(bob);
+    harness.wait_until_tasks(0).await;
+}
+
+#[tokio::test]
+async fn connections_per_username_are_capped() {
+    let harness = Harness::start_with(IoConfig { max_connections_per_user: 2, ..IoConfig::default() }).await;
+    let _first = harness.connect("alice").await;
+    let _second = harness.connect("alice").await;
+    harness.wait_until_connected(2).await;
+
+    let mut third = harness.connect("alice").await;
+    assert_eq!(close_code(&mut third).await, 4429);
+    assert_eq!(harness.connected(), 2);
+
+    // Other usernames aren't affected
+    let _bob = harness.connect("bob").await;
+    harness.wait_until_connected(3).await;
+}
+
+#[tokio::test]
+async fn reconnecting_client_gets_missed_messages() {
+    let harness = Harness::start().await;
+    let mut alice = harness.connect("alice").await;
+    harness.wait_until_connected(1).await;
+
+    harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "first")).await;
+    let last_id = receive(&mut alice).await["id"].as_u64().unwrap();
+    drop(alice);
+    harness.wait_until_connected(0).await;
+    harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "second")).await;
+
+    let (_, body) = harness.post("/v1/websocket/io/token", API_KEY, json!({ "username": "alice" })).await;
+    let url = format!("ws://{}/websocket/io/alice?token={}&last_id={}", harness.host, body["token"].as_str().unwrap(), last_id);
+    let (mut alice, _) = connect_async(url).await.unwrap();
+    assert_eq!(receive(&mut alice).await["bgm"], "second");
+    assert_silent(&mut alice).await;
+}
+
+#[tokio::test]
+async fn requests_without_a_valid_api_key_are_refused() {
+    let harness = Harness::start().await;
+    let (status, _) = harness.post("/v1/websocket/io/send", "wrong-key", play_bgm("alice", "theme")).await;
+    assert_eq!(status, 401);
+    let (status, _) = harness.post("/v1/websocket/io/token", "wrong-key", json!({ "username": "alice" })).await;
+    assert_eq!(status, 401);
+}
+
+#[tokio::test]
+async fn tokens_need_a_lifetime() {
+    let harness = Harness::start().await;
+    let (status, _) = harness.post("/v1/websocket/io/token", API_KEY, json!({ "username": "alice", "ttlSeconds": 0 })).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn websocket_needs_a_token_for_its_username() {
+    let harness = Harness::start().await;
+    assert!(connect_async(format!("ws://{}/websocket/io/alice", harness.host)).await.is_err());
+
+    let (_, body) = harness.post("/v1/websocket/io/token", API_KEY, json!({ "username": "bob" })).await;
+    let url = format!("ws://{}/websocket/io/alice?token={}", harness.host, body["token"].as_str().unwrap());
+    assert!(connect_async(url).await.is_err());
+    assert_eq!(harness.connected(), 0);
+}
+
+#[tokio::test]
+async fn rate_limited_batches_use_up_nothing() {
+    let harness = Harness::start_with(IoConfig {
+        rate_limit_per_target: 1,
+        rate_limit_window: Duration::from_secs(60 * 60),
+        ..IoConfig::default()
+    }).await;
+
+    let (status, _) = harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "theme")).await;
+    assert_eq!(status, 200);
+    let body = json!({ "usernames": ["bob", "alice"], "action": "stop_bgm" });
+    let response = harness.request("/v1/websocket/io/send_batch", API_KEY, body).await;
+    assert_eq!(response.status().as_u16(), 429);
+    // Alice's bucket only refills at the end of the hour long window
+    let retry_after: u64 = response.headers()["Retry-After"].to_str().unwrap().parse().unwrap();
+    assert!((1..=60 * 60).contains(&retry_after), "Retry-After is {}", retry_after);
+
+    // Bob was still under the limit, and nothing was taken from that bucket
+    let (status, _) = harness.post("/v1/websocket/io/send", API_KEY, play_bgm("bob", "theme")).await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn acknowledged_messages_report_it() {
+    let harness = Harness::start().await;
+    let mut alice = harness.connect("alice").await;
+    harness.wait_until_connected(1).await;
+
+    let acknowledge = async {
+        let id = receive(&mut alice).await["id"].as_u64().unwrap();
+        alice.send(Message::Text(json!({ "type": "ack", "id": id }).to_string())).await.unwrap();
+    };
+    let mut body = play_bgm("alice", "theme");
+    body["ackTimeoutMs"] = json!(2000);
+    let ((_, body), _) = tokio::join!(harness.post("/v1/websocket/io/send", API_KEY, body), acknowledge);
+    assert_eq!(body["status"], "acknowledged");
+}
+
+#[tokio::test]
+async fn msgpack_clients_get_binary_frames() {
+    let harness = Harness::start().await;
+    let mut alice = harness.connect_msgpack("alice").await;
+    harness.wait_until_connected(1).await;
+
+    let (_, body) = harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "theme")).await;
+    let message = receive_msgpack(&mut alice).await;
+    assert_eq!(message["client"], "alice");
+    assert_eq!(message["action"], "play_bgm");
+    assert_eq!(message["bgm"], "theme");
+    assert_eq!(message["id"], body["id"]);
+
+    let acknowledge = async {
+        let id = receive_msgpack(&mut alice).await["id"].as_u64().unwrap();
+        let ack = rmp_serde::to_vec_named(&json!({ "type": "ack", "id": id })).unwrap();
+        alice.send(Message::Binary(ack)).await.unwrap();
+    };
+    let mut body = play_bgm("alice", "menu");
+    body["ackTimeoutMs"] = json!(2000);
+    let ((_, body), _) = tokio::join!(harness.post("/v1/websocket/io/send", API_KEY, body), acknowledge);
+    assert_eq!(body["status"], "acknowledged");
+}
+
+#[tokio::test]
+async fn scheduling_needs_a_time() {
+    let harness = Harness::start().await;
+    let mut body = play_bgm("alice", "theme");
+    body["schedule"] = json!(true);
+    let (status, _) = harness.post("/v1/websocket/io/send", API_KEY, body).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn scheduling_is_capped() {
+    let harness = Harness::start_with(IoConfig {
+        max_schedule_ahead: Duration::from_secs(60),
+        max_scheduled: 1,
+        ..IoConfig::default()
+    }).await;
+    let scheduled = |username: &str, ahead: Duration| {
+        let mut body = play_bgm(username, "theme");
+        body["schedule"] = json!(true);
+        body["utc_time"] = json!(unix_millis() + ahead.as_millis() as u64);
+        body
+    };
+
+    let (status, _) = harness.post("/v1/websocket/io/send", API_KEY, scheduled("alice", Duration::from_secs(120))).await;
+    assert_eq!(status, 400);
+    let (status, body) = harness.post("/v1/websocket/io/send", API_KEY, scheduled("alice", Duration::from_secs(30))).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "scheduled");
+    // The one above is still waiting, so there's no room for another
+    let (status, _) = harness.post("/v1/websocket/io/send", API_KEY, scheduled("bob", Duration::from_secs(30))).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn sse_events_carry_the_message_id() {
+    let harness = Harness::start_with(fast_heartbeat()).await;
+    let mut alice = harness.open_sse("alice", None).await;
+    harness.wait_until_connected(1).await;
+    assert_eq!(harness.connection_tasks(), 1);
+
+    let (_, body) = harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "theme")).await;
+    let (id, message) = alice.next().await;
+    assert_eq!(id, body["id"].to_string());
+    assert_eq!(message["bgm"], "theme");
+
+    drop(alice);
+    harness.wait_until_connected(0).await;
+    harness.wait_until_tasks(0).await;
+}
+
+#[tokio::test]
+async fn sse_resumes_from_the_last_event_id() {
+    let harness = Harness::start_with(fast_heartbeat()).await;
+    let mut alice = harness.open_sse("alice", None).await;
+    harness.wait_until_connected(1).await;
+
+    harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "first")).await;
+    let (last_id, _) = alice.next().await;
+    drop(alice);
+    harness.wait_until_connected(0).await;
+    harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "second")).await;
+
+    let mut alice = harness.open_sse("alice", Some(&last_id)).await;
+    assert_eq!(alice.next().await.1["bgm"], "second");
+}
+
+#[tokio::test]
+async fn sse_clients_acknowledge_through_a_post() {
+    let harness = Harness::start_with(fast_heartbeat()).await;
+    let mut alice = harness.open_sse("alice", None).await;
+    harness.wait_until_connected(1).await;
+
+    let acknowledge = async {
+        let (id, _) = alice.next().await;
+        let status = harness.sse_message("alice", json!({ "type": "ack", "id": id.parse::<u64>().unwrap() })).await;
+        assert_eq!(status, 204);
+    };
+    let mut body = play_bgm("alice", "theme");
+    body["ackTimeoutMs"] = json!(2000);
+    let ((_, body), _) = tokio::join!(harness.post("/v1/websocket/io/send", API_KEY, body), acknowledge);
+    assert_eq!(body["status"], "acknowledged");
+}
+
+#[tokio::test]
+async fn observers_need_the_api_key_header() {
+    let harness = Harness::start().await;
+    let url = format!("ws://{}/websocket/observer/dashboard", harness.host);
+    assert!(connect_async(format!("{}?api_key={}", url, API_KEY)).await.is_err());
+
+    let mut request = url.into_client_request().unwrap();
+    request.headers_mut().insert("x-api-key", API_KEY.parse().unwrap());
+    assert!(connect_async(request).await.is_ok());
+}
+
+#[tokio::test]
+async fn observers_see_sends_deliveries_and_acks() {
+    let harness = Harness::start().await;
+    let mut observer = harness.observe().await;
+    harness.wait_until_tasks(1).await;
+    let mut alice = harness.connect("alice").await;
+    harness.wait_until_connected(1).await;
+
+    harness.post("/v1/websocket/io/send", API_KEY, play_bgm("alice", "theme")).await;
+    let id = receive(&mut alice).await["id"].as_u64().unwrap();
+    alice.send(Message::Text(json!({ "type": "ack", "id": id }).to_string())).await.unwrap();
+
+    let delivered = receive(&mut observer).await;
+    assert_eq!(delivered["event"], "deliver");
+    assert_eq!(delivered["id"], id);
+    assert_eq!(delivered["client"], "alice");
+    let sent = receive(&mut observer).await;
+    assert_eq!(sent["event"], "send");
+    assert_eq!(sent["message"]["id"], id);
+    assert_eq!(sent["status"], "sent");
+    let acknowledged = receive(&mut observer).await;
+    assert_eq!(acknowledged["event"], "ack");
+    assert_eq!(acknowledged["id"], id);
+}
+
+#[tokio::test]
+async fn unknown_join_types_are_closed() {
+    let harness = Harness::start().await;
+    let (mut socket, _) = connect_async(format!("ws://{}/websocket/spectator/alice", harness.host)).await.unwrap();
+    assert_eq!(close_code(&mut socket).await, 4400);
+}
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use full_moon::{
+    ast::{Assignment, Ast, Block, Call, Expression, Field, FunctionArgs, FunctionBody, FunctionCall, FunctionDeclaration, GenericFor, Index, LocalAssignment, LocalFunction, NumericFor, Parameter, Prefix, Suffix, Var},
+    visitors::Visitor
+};
+use super::{destructive::token_text, position_of, rules::{self, ScanRule}, Finding};
+
+// Globals that stay forbidden no matter what name they end up being called by
+const FORBIDDEN_GLOBALS: &[&str] = &["getfenv", "setfenv", "require"];
+// Every new alias can lead to more, so give up on scripts that keep producing them
+pub(super) const MAX_PASSES: usize = 16;
+
+fn rule_for(global: &str) -> &'static ScanRule {
+    match global {
+        "getfenv" => &rules::GETFENV_USAGE,
+        "setfenv" => &rules::SETFENV_USAGE,
+        _ => &rules::REQUIRE_BY_ID
+    }
+}
+
+// `a.b.c` for plain indexing chains, None as soon as there's a call or a computed index in the way
+fn var_path(var: &Var) -> Option<String> {
+    match var {
+        Var::Name(token) => Some(token_text(token)),
+        Var::Expression(var_expression) => {
+            let Prefix::Name(root) = var_expression.prefix() else {
+                return None
+            };
+            let mut path = token_text(root);
+            for suffix in var_expression.suffixes() {
+                path.push('.');
+                path.push_str(&index_name(suffix)?);
+            }
+            Some(path)
+        },
+        _ => None
+    }
+}
+
+fn index_name(suffix: &Suffix) -> Option<String> {
+    match suffix {
+        Suffix::Index(Index::Dot { name, .. }) => Some(token_text(name)),
+        Suffix::Index(Index::Brackets { expression: Expression::String(token), .. }) => Some(token_text(token)),
+        _ => None
+    }
+}
+
+// The function being called and its arguments, `t.f(x)` and `t:f(x)` both give `t.f`
+fn callee(call: &FunctionCall) -> Option<(String, &FunctionArgs)> {
+    let Prefix::Name(root) = call.prefix() else {
+        return None
+    };
+    let mut path = token_text(root);
+    for suffix in call.suffixes() {
+        match suffix {
+            Suffix::Call(Call::AnonymousCall(args)) => return Some((path, args)),
+            Suffix::Call(Call::MethodCall(method)) => {
+                path.push('.');
+                path.push_str(&token_text(method.name()));
+                return Some((path, method.args()))
+            },
+            _ => {
+                path.push('.');
+                path.push_str(&index_name(suffix)?);
+            }
+        }
+    }
+    None
+}
+
+pub(super) fn arguments(args: &FunctionArgs) -> Vec<&Expression> {
+    match args {
+        FunctionArgs::Parentheses { arguments, .. } => arguments.iter().collect(),
+        _ => Vec::new()
+    }
+}
+
+pub(super) fn parameter_names(body: &FunctionBody) -> Vec<Option<String>> {
+    body.parameters().iter().map(|parameter| match parameter {
+        Parameter::Name(token) => Some(token_text(token)),
+        _ => None
+    }).collect()
+}
+
+// Which local each name refers to at the current point of the walk, so two locals that share a name stay apart.
+// Locals get a key like `r#3` from the order they're declared in, globals keep their plain name.
+#[derive(Default)]
+struct Scopes {
+    // Innermost scope last, name -> key
+    stack: Vec<HashMap<String, String>>,
+    // Locals declared so far in this pass, the walk is the same every pass so a local gets the same key each time
+    declared: usize
+}
+
+impl Scopes {
+    fn push(&mut self) {
+        self.stack.push(HashMap::new());
+    }
+
+    fn pop(&mut self) {
+        self.stack.pop();
+    }
+
+    fn declare(&mut self, name: String) -> String {
+        self.declared += 1;
+        let key = format!("{}#{}", name, self.declared);
+        if let Some(scope) = self.stack.last_mut() {
+            scope.insert(name, key.clone());
+        }
+        key
+    }
+
+    fn resolve(&self, name: &str) -> String {
+        self.stack.iter().rev().find_map(|scope| scope.get(name).cloned()).unwrap_or_else(|| name.to_string())
+    }
+
+    // `t.f` becomes `t#2.f` when `t` is a local
+    fn key(&self, path: &str) -> String {
+        match path.split_once('.') {
+            Some((root, rest)) => format!("{}.{}", self.resolve(root), rest),
+            None => self.resolve(path)
+        }
+    }
+}
+
+// What a value being assigned can hand on to the name it's assigned to
+enum Value {
+    // One of the forbidden globals
+    Alias(String),
+    // (field, global) for table fields holding one
+    Table(Vec<(String, String)>),
+    // Position of the function's body
+    Function(usize)
+}
+
+#[derive(Default)]
+struct AliasCollector {
+    scopes: Scopes,
+    // alias key -> forbidden global it came from
+    aliases: HashMap<String, String>,
+    // function key -> position of its body
+    functions: HashMap<String, usize>,
+    // function body position -> keys of its parameters, for following aliases into calls
+    parameters: HashMap<usize, Vec<Option<String>>>,
+    changed: bool,
+    // Only set on the last pass, once every alias is known
+    findings: Option<Vec<Finding>>
+}
+
+impl AliasCollector {
+    fn origin(&self, expression: &Expression) -> Option<String> {
+        match expression {
+            Expression::Var(var) => {
+                let key = self.scopes.key(&var_path(var)?);
+                if FORBIDDEN_GLOBALS.contains(&key.as_str()) {
+                    Some(key)
+                } else {
+                    self.aliases.get(&key).cloned()
+                }
+            },
+            Expression::Parentheses { expression, .. } => self.origin(expression),
+            Expression::TypeAssertion { expression, .. } => self.origin(expression),
+            // `local f = something or getfenv`
+            Expression::BinaryOperator { lhs, rhs, .. } => self.origin(lhs).or_else(|| self.origin(rhs)),
+            _ => None
+        }
+    }
+
+    fn value(&self, expression: &Expression) -> Option<Value> {
+        if let Some(origin) = self.origin(expression) {
+            return Some(Value::Alias(origin))
+        }
+
+        match expression {
+            Expression::TableConstructor(table) => {
+                let fields = table.fields().iter().filter_map(|field| {
+                    let (key, value) = match field {
+                        Field::NameKey { key, value, .. } => (token_text(key), value),
+                        Field::ExpressionKey { key: Expression::String(key), value, .. } => (token_text(key), value),
+                        _ => return None
+                    };
+                    Some((key, self.origin(value)?))
+                }).collect();
+                Some(Value::Table(fields))
+            },
+            Expression::Function(function) => Some(Value::Function(position_of(&function.1))),
+            _ => None
+        }
+    }
+
+    fn alias(&mut self, key: String, origin: String) {
+        if !self.aliases.contains_key(&key) && !FORBIDDEN_GLOBALS.contains(&key.as_str()) {
+            self.aliases.insert(key, origin);
+            self.changed = true;
+        }
+    }
+
+    fn function(&mut self, key: String, position: usize) {
+        if self.functions.insert(key, position) != Some(position) {
+            self.changed = true;
+        }
+    }
+
+    fn assign(&mut self, key: String, value: Option<Value>) {
+        match value {
+            Some(Value::Alias(origin)) => self.alias(key, origin),
+            Some(Value::Table(fields)) => {
+                for (field, origin) in fields {
+                    self.alias(format!("{}.{}", key, field), origin);
+                }
+            },
+            Some(Value::Function(position)) => self.function(key, position),
+            None => {}
+        }
+    }
+
+    fn report(&mut self, position: usize, origin: &str, args: &FunctionArgs, reason: String) {
+        // Requiring is only a problem when it's done by id
+        let by_id = arguments(args).iter().any(|argument| matches!(argument, Expression::Number(_)));
+        if origin == "require" && !by_id {
+            return
+        }
+        if let Some(findings) = self.findings.as_mut() {
+            findings.push(Finding { position, rule: rule_for(origin), reason, target: None });
+        }
+    }
+
+    fn find(&mut self, call: &FunctionCall, path: &str, key: &str, args: &FunctionArgs) {
+        // Direct calls to the globals are already picked up by `luau_find_global_function_usage`
+        if let Some(origin) = self.aliases.get(key).cloned() {
+            let reason = format!("Detected `{}` being called through the alias `{}`.", origin, path);
+            self.report(position_of(call), &origin, args, reason);
+        }
+
+        // Handing the function itself to something we can't follow, e.g. `pcall(getfenv, 2)`
+        if !self.functions.contains_key(key) {
+            for argument in arguments(args) {
+                if let Some(origin) = self.origin(argument) {
+                    let reason = format!("Detected `{}` being passed into `{}`, which calls it indirectly.", origin, path);
+                    self.report(position_of(argument), &origin, args, reason);
+                }
+            }
+        }
+    }
+}
+
+impl Visitor for AliasCollector {
+    fn visit_block(&mut self, _block: &Block) {
+        self.scopes.push();
+    }
+
+    fn visit_block_end(&mut self, _block: &Block) {
+        self.scopes.pop();
+    }
+
+    fn visit_function_body(&mut self, body: &FunctionBody) {
+        self.scopes.push();
+        let parameters = parameter_names(body).into_iter().map(|name| name.map(|name| self.scopes.declare(name))).collect();
+        self.parameters.insert(position_of(body), parameters);
+    }
+
+    fn visit_function_body_end(&mut self, _body: &FunctionBody) {
+        self.scopes.pop();
+    }
+
+    // Loop variables only shadow other names, they never hold an alias themselves
+    fn visit_numeric_for(&mut self, numeric_for: &NumericFor) {
+        self.scopes.push();
+        self.scopes.declare(token_text(numeric_for.index_variable()));
+    }
+
+    fn visit_numeric_for_end(&mut self, _numeric_for: &NumericFor) {
+        self.scopes.pop();
+    }
+
+    fn visit_generic_for(&mut self, generic_for: &GenericFor) {
+        self.scopes.push();
+        for name in generic_for.names().iter() {
+            self.scopes.declare(token_text(name));
+        }
+    }
+
+    fn visit_generic_for_end(&mut self, _generic_for: &GenericFor) {
+        self.scopes.pop();
+    }
+
+    fn visit_local_assignment(&mut self, assignment: &LocalAssignment) {
+        // The values are read before the new locals exist, `local r = r` still means the outer `r`
+        let mut values: Vec<Option<Value>> = assignment.expressions().iter().map(|expression| self.value(expression)).collect();
+        values.resize_with(assignment.names().len(), || None);
+        for (name, value) in assignment.names().iter().zip(values) {
+            let key = self.scopes.declare(token_text(name));
+            self.assign(key, value);
+        }
+    }
+
+    fn visit_assignment(&mut self, assignment: &Assignment) {
+        for (var, expression) in assignment.variables().iter().zip(assignment.expressions().iter()) {
+            if let Some(path) = var_path(var) {
+                let key = self.scopes.key(&path);
+                let value = self.value(expression);
+                self.assign(key, value);
+            }
+        }
+    }
+
+    // The name is in scope inside its own body, so it's declared before the body is walked
+    fn visit_local_function(&mut self, function: &LocalFunction) {
+        let key = self.scopes.declare(token_text(function.name()));
+        self.function(key, position_of(function.body()));
+    }
+
+    fn visit_function_declaration(&mut self, function: &FunctionDeclaration) {
+        let mut path: Vec<String> = function.name().names().iter().map(token_text).collect();
+        if let Some(method) = function.name().method_name() {
+            path.push(token_text(method));
+        }
+        let key = self.scopes.key(&path.join("."));
+        self.function(key, position_of(function.body()));
+    }
+
+    fn visit_function_call(&mut self, call: &FunctionCall) {
+        let Some((path, args)) = callee(call) else {
+            return
+        };
+        let key = self.scopes.key(&path);
+
+        let parameters = self.functions.get(&key).and_then(|body| self.parameters.get(body)).cloned();
+        for (argument, parameter) in arguments(args).into_iter().zip(parameters.unwrap_or_default()) {
+            if let (Some(origin), Some(parameter)) = (self.origin(argument), parameter) {
+                self.alias(parameter, origin);
+            }
+        }
+
+        if self.findings.is_some() {
+            self.find(call, &path, &key, args);
+        }
+    }
+}
+
+pub fn find_aliased_globals(ast: &Ast) -> Vec<Finding> {
+    let mut collector = AliasCollector::default();
+    for _ in 0..MAX_PASSES {
+        collector.changed = false;
+        collector.scopes = Scopes::default();
+        collector.visit_ast(ast);
+        if !collector.changed {
+            break;
+        }
+    }
+
+    collector.findings = Some(Vec::new());
+    collector.scopes = Scopes::default();
+    collector.visit_ast(ast);
+    collector.findings.unwrap_or_default()
+}
@@ -0,0 +1,277 @@
+use std::collections::{hash_map::Entry, HashMap};
+use full_moon::{
+    ast::{Assignment, Ast, Call, CompoundAssignment, Expression, FunctionArgs, FunctionCall, Index, LocalAssignment, Prefix, Suffix, Var},
+    node::Node,
+    tokenizer::{TokenReference, TokenType},
+    visitors::Visitor
+};
+use super::{aliases, rules::{self, ScanRule}, Finding};
+
+// Services a map has no business touching, Workspace is handled separately since maps live in it
+const PROTECTED_SERVICES: &[&str] = &[
+    "Players", "Lighting", "ServerStorage", "ServerScriptService", "ReplicatedStorage", "ReplicatedFirst",
+    "StarterGui", "StarterPack", "StarterPlayer", "SoundService", "Teams", "Chat", "TextChatService"
+];
+const DESTRUCTIVE_METHODS: &[&str] = &["ClearAllChildren", "Destroy", "Remove"];
+
+// One step of an indexing chain like `game:GetService("Players").Bob:Kick()`
+enum Link {
+    Field(String),
+    Method(String, Option<String>),
+    Call,
+    Unknown
+}
+
+pub(super) fn token_text(token: &TokenReference) -> String {
+    match token.token().token_type() {
+        TokenType::StringLiteral { literal, .. } => literal.to_string(),
+        _ => token.token().to_string()
+    }
+}
+
+fn first_string_argument(args: &FunctionArgs) -> Option<String> {
+    match args {
+        FunctionArgs::String(token) => Some(token_text(token)),
+        FunctionArgs::Parentheses { arguments, .. } => match arguments.first().map(|pair| pair.value()) {
+            Some(Expression::String(token)) => Some(token_text(token)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+fn links<'a>(suffixes: impl Iterator<Item = &'a Suffix>) -> Vec<Link> {
+    suffixes.map(|suffix| match suffix {
+        Suffix::Index(Index::Dot { name, .. }) => Link::Field(token_text(name)),
+        Suffix::Index(Index::Brackets { expression: Expression::String(token), .. }) => Link::Field(token_text(token)),
+        Suffix::Call(Call::MethodCall(method)) => Link::Method(token_text(method.name()), first_string_argument(method.args())),
+        Suffix::Call(Call::AnonymousCall(_)) => Link::Call,
+        _ => Link::Unknown
+    }).collect()
+}
+
+fn root_name(prefix: &Prefix) -> Option<String> {
+    match prefix {
+        Prefix::Name(token) => Some(token_text(token)),
+        _ => None
+    }
+}
+
+fn is_known_service(name: &str) -> bool {
+    name == "Workspace" || PROTECTED_SERVICES.contains(&name)
+}
+
+// Links past which a chain isn't touching its service anymore: a clone is a fresh copy, and a player's character
+// lives in Workspace
+fn leaves_service(link: &Link) -> bool {
+    match link {
+        Link::Method(method, _) => method == "Clone",
+        Link::Field(name) => name == "Character",
+        _ => false
+    }
+}
+
+// Where a chain starts: its service, how far inside the service the root already is, and the first link past it
+struct ChainStart {
+    service: String,
+    depth: usize,
+    start: usize
+}
+
+// Names bound to something inside a service, like `local Lighting = game:GetService("Lighting")`.
+// Bindings are followed by name rather than by scope, a local reusing a bound name counts as bound too.
+#[derive(Default)]
+struct ServiceBindings {
+    // name -> (service, how far inside it)
+    bound: HashMap<String, (String, usize)>,
+    changed: bool
+}
+
+impl ServiceBindings {
+    fn resolve(&self, root: &str, links: &[Link]) -> Option<ChainStart> {
+        match root {
+            "workspace" | "Workspace" => Some(ChainStart { service: "Workspace".to_string(), depth: 0, start: 0 }),
+            "game" | "Game" => {
+                let service = match links.first() {
+                    Some(Link::Field(name)) => name.clone(),
+                    Some(Link::Method(method, Some(name))) if method == "GetService" || method == "FindService" => name.clone(),
+                    _ => return None
+                };
+                let service = if service == "workspace" { "Workspace".to_string() } else { service };
+                if is_known_service(&service) { Some(ChainStart { service, depth: 0, start: 1 }) } else { None }
+            },
+            _ => self.bound.get(root).map(|(service, depth)| ChainStart { service: service.clone(), depth: *depth, start: 0 })
+        }
+    }
+
+    fn resolve_expression(&self, expression: &Expression) -> Option<(String, usize)> {
+        let (root, links) = match expression {
+            Expression::Var(Var::Name(token)) => (token_text(token), Vec::new()),
+            Expression::Var(Var::Expression(var_expression)) => (root_name(var_expression.prefix())?, links(var_expression.suffixes())),
+            Expression::FunctionCall(call) => (root_name(call.prefix())?, links(call.suffixes())),
+            Expression::Parentheses { expression, .. } => return self.resolve_expression(expression),
+            Expression::TypeAssertion { expression, .. } => return self.resolve_expression(expression),
+            _ => return None
+        };
+        let chain = self.resolve(&root, &links)?;
+        let inside = &links[chain.start..];
+        if inside.iter().any(leaves_service) {
+            return None
+        }
+        Some((chain.service, chain.depth + inside.len()))
+    }
+
+    fn bind(&mut self, name: String, binding: (String, usize)) {
+        if let Entry::Vacant(entry) = self.bound.entry(name) {
+            entry.insert(binding);
+            self.changed = true;
+        }
+    }
+
+    fn check_call(&self, root: &str, links: &[Link]) -> Option<(&'static ScanRule, String, String)> {
+        // Only a Player has a `Kick` method, so it's flagged whatever the player came from,
+        // e.g. a loop over `Players:GetPlayers()` that isn't bound to anything
+        if links.iter().any(|link| matches!(link, Link::Method(method, _) if method == "Kick")) {
+            return Some((&rules::DESTRUCTIVE_KICK, "Players".to_string(), "Detected a player being kicked from the game.".to_string()))
+        }
+
+        if root == "game" || root == "Game" {
+            if let Some(Link::Method(method, _)) = links.first() {
+                if DESTRUCTIVE_METHODS.contains(&method.as_str()) {
+                    return Some((&rules::DESTRUCTIVE_CLEAR, "game".to_string(), format!("Detected `game:{}()`, which wipes the entire game.", method)))
+                }
+            }
+        }
+
+        let ChainStart { service, depth, start } = self.resolve(root, links)?;
+        for (index, link) in links[start..].iter().enumerate() {
+            if leaves_service(link) {
+                break;
+            }
+            if let Link::Method(method, _) = link {
+                if DESTRUCTIVE_METHODS.contains(&method.as_str()) {
+                    if depth + index == 0 {
+                        return Some((&rules::DESTRUCTIVE_CLEAR, service.clone(), format!("Detected `{}` being wiped with `{}`.", service, method)))
+                    }
+                    if service != "Workspace" {
+                        return Some((&rules::DESTRUCTIVE_CLEAR, service.clone(), format!("Detected `{}` being used on something inside `{}`.", method, service)))
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn check_assignment(&self, var: &Var) -> Option<(&'static ScanRule, String, String)> {
+        let var_expression = match var {
+            Var::Expression(var_expression) => var_expression,
+            _ => return None
+        };
+        let root = root_name(var_expression.prefix())?;
+        let links = links(var_expression.suffixes());
+        let chain = self.resolve(&root, &links)?;
+        let inside = &links[chain.start..];
+        if inside.iter().any(leaves_service) {
+            return None
+        }
+        if chain.service != "Workspace" && chain.depth + inside.len() > 0 {
+            return Some((&rules::DESTRUCTIVE_GLOBAL_MODIFY, chain.service.clone(), format!("Detected an assignment to `{}` outside of the map.", chain.service)))
+        }
+        None
+    }
+}
+
+impl Visitor for ServiceBindings {
+    fn visit_local_assignment(&mut self, assignment: &LocalAssignment) {
+        for (name, expression) in assignment.names().iter().zip(assignment.expressions().iter()) {
+            if let Some(binding) = self.resolve_expression(expression) {
+                self.bind(token_text(name), binding);
+            }
+        }
+    }
+
+    fn visit_assignment(&mut self, assignment: &Assignment) {
+        for (var, expression) in assignment.variables().iter().zip(assignment.expressions().iter()) {
+            if let (Var::Name(name), Some(binding)) = (var, self.resolve_expression(expression)) {
+                self.bind(token_text(name), binding);
+            }
+        }
+    }
+
+    // `Players.PlayerAdded:Connect(function(player) ... end)` hands its callback a player
+    fn visit_function_call(&mut self, call: &FunctionCall) {
+        let Some(root) = root_name(call.prefix()) else {
+            return
+        };
+        let call_links = links(call.suffixes());
+        let Some(chain) = self.resolve(&root, &call_links) else {
+            return
+        };
+        if chain.service != "Players" {
+            return
+        }
+
+        for (index, suffix) in call.suffixes().enumerate().skip(chain.start + 1) {
+            let (Some(Link::Field(event)), Suffix::Call(Call::MethodCall(method))) = (call_links.get(index - 1), suffix) else {
+                continue
+            };
+            if event != "PlayerAdded" || token_text(method.name()) != "Connect" {
+                continue
+            }
+            if let Some(Expression::Function(function)) = aliases::arguments(method.args()).first() {
+                if let Some(Some(player)) = aliases::parameter_names(&function.1).into_iter().next() {
+                    self.bind(player, ("Players".to_string(), 1));
+                }
+            }
+        }
+    }
+}
+
+struct DestructiveVisitor<'a> {
+    bindings: &'a ServiceBindings,
+    findings: Vec<Finding>
+}
+
+impl DestructiveVisitor<'_> {
+    fn push(&mut self, node: &impl Node, found: Option<(&'static ScanRule, String, String)>) {
+        if let Some((rule, target, reason)) = found {
+            self.findings.push(Finding { position: super::position_of(node), rule, reason, target: Some(target) });
+        }
+    }
+}
+
+impl Visitor for DestructiveVisitor<'_> {
+    fn visit_function_call(&mut self, call: &FunctionCall) {
+        if let Some(root) = root_name(call.prefix()) {
+            let found = self.bindings.check_call(&root, &links(call.suffixes()));
+            self.push(call, found);
+        }
+    }
+
+    fn visit_assignment(&mut self, assignment: &Assignment) {
+        for var in assignment.variables().iter() {
+            let found = self.bindings.check_assignment(var);
+            self.push(var, found);
+        }
+    }
+
+    fn visit_compound_assignment(&mut self, assignment: &CompoundAssignment) {
+        let found = self.bindings.check_assignment(assignment.lhs());
+        self.push(assignment.lhs(), found);
+    }
+}
+
+pub fn find_destructive_operations(ast: &Ast) -> Vec<Finding> {
+    let mut bindings = ServiceBindings::default();
+    for _ in 0..aliases::MAX_PASSES {
+        bindings.changed = false;
+        bindings.visit_ast(ast);
+        if !bindings.changed {
+            break;
+        }
+    }
+
+    let mut visitor = DestructiveVisitor { bindings: &bindings, findings: Vec::new() };
+    visitor.visit_ast(ast);
+    visitor.findings
+}
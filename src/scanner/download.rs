@@ -0,0 +1,50 @@
+// Downloads map assets ourselves instead of through the backend, so the size cap applies while downloading
+// rather than after the whole file is already in memory.
+const ASSET_URL: &str = "https://assetdelivery.roblox.com/v1/asset/?id=";
+
+pub enum DownloadError {
+    // Size of the asset as far as we know, either from Content-Length or how much arrived before we gave up
+    TooLarge(u64),
+    Failed(String)
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Failed(e.to_string())
+    }
+}
+
+pub struct AssetDownloader {
+    client: reqwest::Client,
+    roblox_cookie: String
+}
+
+impl AssetDownloader {
+    pub fn new(roblox_cookie: String) -> Self {
+        Self { client: reqwest::Client::new(), roblox_cookie }
+    }
+
+    pub async fn download(&self, asset_id: u64, max_bytes: usize) -> Result<Vec<u8>, DownloadError> {
+        let mut response = self.client.get(format!("{}{}", ASSET_URL, asset_id))
+            .header("Cookie", format!(".ROBLOSECURITY={}", self.roblox_cookie))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        if let Some(length) = response.content_length() {
+            if length > max_bytes as u64 {
+                return Err(DownloadError::TooLarge(length))
+            }
+        }
+
+        // Content-Length can be missing or wrong, so keep counting while the body comes in
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if bytes.len() + chunk.len() > max_bytes {
+                return Err(DownloadError::TooLarge((bytes.len() + chunk.len()) as u64))
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+}
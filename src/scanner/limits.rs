@@ -0,0 +1,45 @@
+use std::time::Duration;
+use crate::config::env_or;
+
+// Caps for a single map scan, so a huge or malicious map can't take the whole VM down with it.
+// Every value can be overridden from the environment, see `ScanLimits::from_env`.
+#[derive(Clone, Debug)]
+pub struct ScanLimits {
+    pub max_download_bytes: usize,
+    pub max_scripts: usize,
+    pub max_source_bytes: usize,
+    pub max_duration: Duration,
+    // Findings kept for one scan, each one holds its own excerpt and is stored for the report
+    pub max_findings: usize,
+    // Scans allowed to run at once, later ones wait for a turn
+    pub max_concurrent_scans: usize
+}
+
+impl Default for ScanLimits {
+    // Sized for the 1 GB fly VM: a map's DOM takes several times its download size once it's decompressed,
+    // and every running scan holds one
+    fn default() -> Self {
+        Self {
+            max_download_bytes: 16 * 1024 * 1024,
+            max_scripts: 2000,
+            max_source_bytes: 16 * 1024 * 1024,
+            max_duration: Duration::from_secs(30),
+            max_findings: 1000,
+            max_concurrent_scans: 2
+        }
+    }
+}
+
+impl ScanLimits {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_download_bytes: env_or("SCAN_MAX_DOWNLOAD_BYTES", default.max_download_bytes),
+            max_scripts: env_or("SCAN_MAX_SCRIPTS", default.max_scripts),
+            max_source_bytes: env_or("SCAN_MAX_SOURCE_BYTES", default.max_source_bytes),
+            max_duration: Duration::from_secs(env_or("SCAN_MAX_SECONDS", default.max_duration.as_secs())),
+            max_findings: env_or("SCAN_MAX_FINDINGS", default.max_findings),
+            max_concurrent_scans: env_or("SCAN_MAX_CONCURRENT", default.max_concurrent_scans)
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use full_moon::node::Node;
+use rules::ScanRule;
+
+pub mod aliases;
+pub mod destructive;
+pub mod download;
+pub mod limits;
+pub mod report;
+pub mod rules;
+pub mod store;
+
+#[cfg(test)]
+mod tests;
+
+// A finding from one of our own AST passes, `position` is a byte offset into the script source
+pub struct Finding {
+    pub position: usize,
+    pub rule: &'static ScanRule,
+    pub reason: String,
+    pub target: Option<String>
+}
+
+fn position_of(node: &impl Node) -> usize {
+    node.start_position().map(|position| position.bytes()).unwrap_or(0)
+}
@@ -0,0 +1,112 @@
+use std::fmt::Write;
+use super::{rules, store::{StoredFinding, StoredScan}};
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; max-width: 960px; margin: 2em auto; padding: 0 1em; color: #222; }
+.verdict { padding: 1em 1.5em; border-radius: 6px; color: #fff; font-size: 1.3em; font-weight: bold; }
+.verdict.malicious { background: #c62828; }
+.verdict.clean { background: #2e7d32; }
+.verdict.incomplete { background: #b26a00; }
+.truncated { margin-top: 0.5em; padding: 0.75em 1.5em; border-radius: 6px; background: #fff3cd; color: #664d03; }
+.finding { border-left: 4px solid #c62828; margin: 1em 0; padding: 0.25em 1em; background: #fafafa; }
+.finding .rule { color: #666; font-size: 0.9em; }
+pre { background: #1e1e1e; color: #ddd; padding: 0.75em; overflow-x: auto; }
+pre .hit { background: #5a1d1d; display: block; }
+.rule-doc { margin: 1em 0; }
+";
+
+fn write_finding(html: &mut String, finding: &StoredFinding) {
+    let _ = write!(
+        html,
+        "<div class=\"finding\"><p><b>Line {}, column {}</b>: {}</p><p class=\"rule\">Rule: <a href=\"#{}\">{}</a></p>",
+        finding.line, finding.column, escape_html(&finding.reason), escape_html(&finding.rule), escape_html(&finding.rule)
+    );
+
+    if !finding.excerpt.is_empty() {
+        html.push_str("<pre>");
+        for (number, line) in finding.excerpt.iter() {
+            let class = if *number as u64 == finding.line { " class=\"hit\"" } else { "" };
+            let _ = writeln!(html, "<span{}>{:>5} | {}</span>", class, number, escape_html(line));
+        }
+        html.push_str("</pre>");
+    }
+
+    if let Some(target) = &finding.target {
+        let _ = write!(html, "<p>Target: {}</p>", escape_html(target));
+    }
+    if let Some(rule) = rules::find_rule(&finding.rule) {
+        let _ = write!(html, "<p>Suggestion: {}</p>", escape_html(rule.remediation));
+    }
+    html.push_str("</div>");
+}
+
+pub fn render(scan: &StoredScan) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Scan report for asset {}</title><style>{}</style></head><body>",
+        scan.asset_id, STYLE
+    );
+    let _ = write!(html, "<h1>Scan report for asset {}</h1><p>Scan id {}, scanned at {} (unix time)</p>", scan.asset_id, escape_html(&scan.id), scan.scanned_at);
+
+    // Nothing found in a scan that was cut short doesn't make the map clean, it gets a verdict of its own
+    match (scan.findings.is_empty(), &scan.truncated) {
+        (false, _) => {
+            let _ = write!(html, "<div class=\"verdict malicious\">Malicious: {} finding(s)</div>", scan.findings.len());
+        },
+        (true, Some(_)) => html.push_str("<div class=\"verdict incomplete\">Incomplete: the map could not be fully scanned</div>"),
+        (true, None) => html.push_str("<div class=\"verdict clean\">No malicious scripts found</div>")
+    }
+    if let Some(reason) = &scan.truncated {
+        let _ = write!(html, "<div class=\"truncated\">This scan was cut short, so the result is incomplete: {}</div>", escape_html(reason));
+    }
+
+    // Group findings by script, keeping the order the scanner found them in
+    let mut scripts: Vec<&str> = Vec::new();
+    for finding in scan.findings.iter() {
+        if !scripts.contains(&finding.script.as_str()) {
+            scripts.push(&finding.script);
+        }
+    }
+    for script in scripts.iter() {
+        let _ = write!(html, "<h2>{}</h2>", escape_html(script));
+        for finding in scan.findings.iter().filter(|finding| finding.script == *script) {
+            write_finding(&mut html, finding);
+        }
+    }
+
+    let mut used_rules: Vec<&str> = Vec::new();
+    for finding in scan.findings.iter() {
+        if !used_rules.contains(&finding.rule.as_str()) {
+            used_rules.push(&finding.rule);
+        }
+    }
+    if !used_rules.is_empty() {
+        html.push_str("<h2>Rules</h2>");
+        for rule in used_rules.into_iter().filter_map(rules::find_rule) {
+            let _ = write!(
+                html,
+                "<div class=\"rule-doc\" id=\"{}\"><h3>{}</h3><p class=\"rule\">{}</p><p>{}</p><p><b>How to fix:</b> {}</p></div>",
+                rule.id, escape_html(rule.title), escape_html(rule.category), escape_html(rule.explanation), escape_html(rule.remediation)
+            );
+        }
+    }
+
+    html.push_str("</body></html>");
+    html
+}
@@ -0,0 +1,89 @@
+// Every finding the map scanner produces points back to one of these rules,
+// so map creators can look up why something got flagged and how to fix it.
+pub struct ScanRule {
+    pub id: &'static str,
+    pub category: &'static str,
+    pub title: &'static str,
+    pub explanation: &'static str,
+    pub remediation: &'static str
+}
+
+impl ScanRule {
+    // Where the long-form explanation for this rule can be fetched from
+    pub fn documentation(&self) -> String {
+        format!("/v1/maptest/scan/rules?id={}", self.id)
+    }
+}
+
+pub const GETFENV_USAGE: ScanRule = ScanRule {
+    id: "getfenv-usage",
+    category: "Environment tampering",
+    title: "Usage of getfenv",
+    explanation: "`getfenv` gives a script access to the environment of other functions and scripts. \
+        Malicious scripts use it to grab hidden globals, hook into the game's own scripts and hide what they are really calling, \
+        which is why any use of it is rejected outright. \
+        Calling it under another name, like `local f = getfenv; f(2)`, is caught as well.",
+    remediation: "Remove the `getfenv` call. If you need a value from another script, pass it explicitly through a ModuleScript, \
+        an attribute or a BindableEvent instead of reading another environment."
+};
+
+pub const SETFENV_USAGE: ScanRule = ScanRule {
+    id: "setfenv-usage",
+    category: "Environment tampering",
+    title: "Usage of setfenv",
+    explanation: "`setfenv` replaces the environment of a function or script. \
+        It lets a script swap out globals like `game` or `require` for other scripts, which breaks the sandbox maps are tested in. \
+        Aliases such as `t.f = setfenv` count the same.",
+    remediation: "Remove the `setfenv` call. Keep your values in locals or a ModuleScript and reference them directly."
+};
+
+pub const REQUIRE_BY_ID: ScanRule = ScanRule {
+    id: "require-by-id",
+    category: "Remote code",
+    title: "Requiring a module by asset id",
+    explanation: "`require(123)` downloads a ModuleScript from the Roblox website at runtime. \
+        The module can be changed by its owner at any time after the map is reviewed, so this is the most common way backdoors get into maps. \
+        `local r = require` followed by `r(123)` is treated the same.",
+    remediation: "Insert the ModuleScript into your map and require it by reference instead, e.g. `require(script.Parent.MyModule)`. \
+        Only include code you have read and understand."
+};
+
+pub const DESTRUCTIVE_CLEAR: ScanRule = ScanRule {
+    id: "destructive-clear",
+    category: "Destructive operations",
+    title: "Clearing or destroying game services",
+    explanation: "Calls like `game:ClearAllChildren()`, `Workspace:ClearAllChildren()` or `:Destroy()` on something inside Players, Lighting or ServerStorage \
+        reach outside the map and wipe parts of the game that every other map and player depends on.",
+    remediation: "Only destroy or clear instances that belong to your map, e.g. `script.Parent.Door:Destroy()`. \
+        Keep references relative to your map model instead of going through `game` or `workspace`."
+};
+
+pub const DESTRUCTIVE_KICK: ScanRule = ScanRule {
+    id: "destructive-kick",
+    category: "Destructive operations",
+    title: "Kicking players",
+    explanation: "Maps are not allowed to remove players from the server. `Player:Kick()` is a common payload in trolling scripts.",
+    remediation: "Remove the `:Kick()` call. If a player should be eliminated, let them die in the map's hazards like any other player."
+};
+
+pub const DESTRUCTIVE_GLOBAL_MODIFY: ScanRule = ScanRule {
+    id: "destructive-global-modify",
+    category: "Destructive operations",
+    title: "Modifying services outside the map",
+    explanation: "Assigning to properties or children of services such as Lighting, ServerStorage or Players changes the game for everyone, \
+        and those changes stay around after your map is over.",
+    remediation: "Keep changes inside your map model. Lighting and other environment settings should be set through the map's settings instead of from a script."
+};
+
+pub const RULES: &[ScanRule] = &[
+    GETFENV_USAGE,
+    SETFENV_USAGE,
+    REQUIRE_BY_ID,
+    DESTRUCTIVE_CLEAR,
+    DESTRUCTIVE_KICK,
+    DESTRUCTIVE_GLOBAL_MODIFY
+];
+
+pub fn find_rule(id: &str) -> Option<&'static ScanRule> {
+    RULES.iter().find(|rule| rule.id == id)
+}
@@ -0,0 +1,77 @@
+use std::{collections::VecDeque, sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+use crate::{routes::structs::MaliciousScriptEntry, util::random_hex};
+
+// How many past scans are kept around for reports, oldest ones get dropped first
+const MAX_STORED_SCANS: usize = 256;
+// Lines of source shown around a finding, and how much of each line is kept
+const EXCERPT_CONTEXT: usize = 2;
+const EXCERPT_LINE_LENGTH: usize = 200;
+// Report links are shared around without an API key, so ids are random enough that they can't be guessed
+const SCAN_ID_BYTES: usize = 16;
+
+// Only what the report shows, the suggestion and documentation link come from the rule when it's rendered
+pub struct StoredFinding {
+    pub script: String,
+    pub line: u64,
+    pub column: u64,
+    pub reason: String,
+    pub target: Option<String>,
+    pub rule: String,
+    // (line number, line content)
+    pub excerpt: Vec<(usize, String)>
+}
+
+impl StoredFinding {
+    pub fn new(entry: &MaliciousScriptEntry, excerpt: Vec<(usize, String)>) -> Self {
+        Self {
+            script: entry.script.clone(),
+            line: entry.line,
+            column: entry.column,
+            reason: entry.reason.clone(),
+            target: entry.target.clone(),
+            rule: entry.rule.clone(),
+            excerpt
+        }
+    }
+}
+
+pub struct StoredScan {
+    pub id: String,
+    pub asset_id: u64,
+    pub scanned_at: u64,
+    pub findings: Vec<StoredFinding>,
+    pub truncated: Option<String>
+}
+
+pub fn excerpt(lines: &[&str], line: usize) -> Vec<(usize, String)> {
+    let first = line.saturating_sub(EXCERPT_CONTEXT).max(1);
+    let last = (line + EXCERPT_CONTEXT).min(lines.len());
+    (first..=last).map(|number| (number, lines[number - 1].chars().take(EXCERPT_LINE_LENGTH).collect())).collect()
+}
+
+pub struct ScanStore {
+    scans: Mutex<VecDeque<StoredScan>>
+}
+
+impl ScanStore {
+    pub fn new() -> Self {
+        Self { scans: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn insert(&self, asset_id: u64, findings: Vec<StoredFinding>, truncated: Option<String>) -> String {
+        let id = random_hex(SCAN_ID_BYTES);
+        let scanned_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+        let mut scans = self.scans.lock().unwrap();
+        if scans.len() >= MAX_STORED_SCANS {
+            scans.pop_front();
+        }
+        scans.push_back(StoredScan { id: id.clone(), asset_id, scanned_at, findings, truncated });
+        id
+    }
+
+    pub fn with_scan<T>(&self, id: &str, f: impl FnOnce(&StoredScan) -> T) -> Option<T> {
+        let scans = self.scans.lock().unwrap();
+        scans.iter().find(|scan| scan.id == id).map(f)
+    }
+}
@@ -0,0 +1,149 @@
+// The AST passes only need a parsed script, so these parse with full_moon directly instead of going through the backend
+use super::{aliases::find_aliased_globals, destructive::find_destructive_operations, Finding};
+
+fn parse(source: &str) -> full_moon::ast::Ast {
+    full_moon::parse(source).expect("test script doesn't parse")
+}
+
+// (rule id, target) of every finding, in the order they were found
+fn summarize(findings: Vec<Finding>) -> Vec<(&'static str, Option<String>)> {
+    findings.into_iter().map(|finding| (finding.rule.id, finding.target)).collect()
+}
+
+fn destructive(source: &str) -> Vec<(&'static str, Option<String>)> {
+    summarize(find_destructive_operations(&parse(source)))
+}
+
+// Alias findings never have a target, so only the rule ids
+fn aliased(source: &str) -> Vec<&'static str> {
+    find_aliased_globals(&parse(source)).into_iter().map(|finding| finding.rule.id).collect()
+}
+
+fn target(service: &str) -> Option<String> {
+    Some(service.to_string())
+}
+
+#[test]
+fn clearing_the_game_is_flagged() {
+    assert_eq!(destructive("game:ClearAllChildren()"), [("destructive-clear", target("game"))]);
+}
+
+#[test]
+fn clearing_workspace_is_flagged() {
+    assert_eq!(destructive("Workspace:ClearAllChildren()"), [("destructive-clear", target("Workspace"))]);
+    assert_eq!(destructive("workspace:ClearAllChildren()"), [("destructive-clear", target("Workspace"))]);
+}
+
+#[test]
+fn kicking_players_is_flagged() {
+    assert_eq!(destructive("game.Players.X:Kick()"), [("destructive-kick", target("Players"))]);
+}
+
+#[test]
+fn kicking_every_player_in_a_loop_is_flagged() {
+    let source = "
+        for _, p in ipairs(game.Players:GetPlayers()) do
+            p:Kick()
+        end
+    ";
+    assert_eq!(destructive(source), [("destructive-kick", target("Players"))]);
+}
+
+#[test]
+fn players_handed_to_player_added_are_followed() {
+    let source = "
+        game:GetService(\"Players\").PlayerAdded:Connect(function(player)
+            player:Kick(\"bye\")
+        end)
+    ";
+    assert_eq!(destructive(source), [("destructive-kick", target("Players"))]);
+}
+
+#[test]
+fn assigning_through_a_bound_service_is_flagged() {
+    let source = "local L = game:GetService(\"Lighting\"); L.ClockTime = 0";
+    assert_eq!(destructive(source), [("destructive-global-modify", target("Lighting"))]);
+}
+
+#[test]
+fn clones_of_service_contents_are_not_flagged() {
+    assert!(destructive("game.ServerStorage.X:Clone().Parent = workspace").is_empty());
+
+    let source = "
+        local ServerStorage = game:GetService(\"ServerStorage\")
+        ServerStorage.X:Clone().Parent = workspace
+    ";
+    assert!(destructive(source).is_empty());
+}
+
+#[test]
+fn destroying_a_character_is_not_flagged() {
+    let source = "
+        game.Players.PlayerAdded:Connect(function(player)
+            player.Character:Destroy()
+        end)
+    ";
+    assert!(destructive(source).is_empty());
+}
+
+#[test]
+fn destroying_inside_the_map_is_not_flagged() {
+    assert!(destructive("script.Parent.Door:Destroy()").is_empty());
+    assert!(destructive("workspace.Map.Door:Destroy()").is_empty());
+}
+
+#[test]
+fn calls_through_a_local_alias_are_flagged() {
+    assert_eq!(aliased("local f = getfenv; f(2)"), ["getfenv-usage"]);
+}
+
+#[test]
+fn aliases_passed_into_local_functions_are_followed() {
+    let source = "
+        local function run(fn)
+            return fn(2)
+        end
+        run(getfenv)
+    ";
+    assert_eq!(aliased(source), ["getfenv-usage"]);
+}
+
+#[test]
+fn aliases_stored_in_table_fields_are_flagged() {
+    let source = "
+        local t = {}
+        t.f = setfenv
+        t.f()
+    ";
+    assert_eq!(aliased(source), ["setfenv-usage"]);
+}
+
+#[test]
+fn globals_handed_to_other_functions_are_flagged() {
+    assert_eq!(aliased("pcall(require, 123)"), ["require-by-id"]);
+    // Requiring a module that's in the map is fine, however it's called
+    assert!(aliased("pcall(require, script.Parent.Module)").is_empty());
+}
+
+#[test]
+fn aliases_stay_in_their_own_scope() {
+    let source = "
+        local function load()
+            local r = require
+        end
+        local function round(r)
+            return r(5)
+        end
+    ";
+    assert!(aliased(source).is_empty());
+
+    let source = "
+        local r = require
+        do
+            local r = math.floor
+            r(5)
+        end
+        r(5)
+    ";
+    assert_eq!(aliased(source), ["require-by-id"]);
+}
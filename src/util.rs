@@ -0,0 +1,6 @@
+// `byte_count` bytes from the OS random number generator as hex, for anything that must not be guessable
+pub fn random_hex(byte_count: usize) -> String {
+    let mut bytes = vec![0u8; byte_count];
+    getrandom::getrandom(&mut bytes).expect("the system random number generator is unavailable");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}